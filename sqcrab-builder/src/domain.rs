@@ -67,7 +67,8 @@ pub struct SqcrabParams {
     name: Option<String>,
     domain: Option<String>,
     type_checking: bool,
-    local_pointer: bool
+    local_pointer: bool,
+    constructor: bool
 }
 
 impl SqcrabParams {
@@ -95,26 +96,29 @@ impl SqcrabParams {
 
 impl Parse for SqcrabParams {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let inputs = syn::punctuated::Punctuated::<syn::ExprAssign, Token![,]>::parse_terminated(input)?;
+        let inputs = syn::punctuated::Punctuated::<syn::Meta, Token![,]>::parse_terminated(input)?;
         let mut name: Option<String> = None;
         let mut domain: Option<String> = None;
         let mut type_checking = false;
         let mut local_pointer = false;
+        let mut constructor = false;
         for input in inputs {
-            if let syn::Expr::Path(p) = input.left.as_ref() {
-                if let Some(p) = p.path.get_ident() {
-                    match p.to_string().as_ref() {
-                        "name" => name = Some(Self::get_name_value(input.right.as_ref())?),
-                        "domain" => domain = Some(Self::get_name_value(input.right.as_ref())?),
-                        "type_checking" => type_checking = Self::get_bool_value(input.right.as_ref())?,
-                        "local_pointer" => type_checking = Self::get_bool_value(input.right.as_ref())?,
-                        _ => return Err(syn::Error::new(Span::call_site(), &format!("Unknown parameter {}", p.to_string())))
+            match input {
+                syn::Meta::NameValue(nv) => {
+                    if let Some(p) = nv.path.get_ident() {
+                        match p.to_string().as_ref() {
+                            "name" => name = Some(Self::get_name_value(&nv.value)?),
+                            "domain" => domain = Some(Self::get_name_value(&nv.value)?),
+                            "type_checking" => type_checking = Self::get_bool_value(&nv.value)?,
+                            "local_pointer" => type_checking = Self::get_bool_value(&nv.value)?,
+                            _ => return Err(syn::Error::new(Span::call_site(), &format!("Unknown parameter {}", p.to_string())))
+                        }
+                    } else {
+                        return Err(syn::Error::new(Span::call_site(), "LHS is missing an identifier"));
                     }
-                } else {
-                    return Err(syn::Error::new(Span::call_site(), "LHS is missing an identifier"));
-                }
-            } else {
-                return Err(syn::Error::new(Span::call_site(), "LHS of argument should be a path"));
+                },
+                syn::Meta::Path(p) if p.is_ident("constructor") => constructor = true,
+                _ => return Err(syn::Error::new(Span::call_site(), "Argument should be a flag or an assignment"))
             }
         }
         /*
@@ -122,7 +126,7 @@ impl Parse for SqcrabParams {
             return Err(syn::Error::new(Span::call_site(), "Name field is required"));
         }
         */
-        Ok(Self { name, domain, type_checking, local_pointer })
+        Ok(Self { name, domain, type_checking, local_pointer, constructor })
     }
 }
 
@@ -131,16 +135,63 @@ impl<'a> TryFrom<&'a syn::Attribute> for SqcrabParams {
 
     fn try_from(value: &'a syn::Attribute) -> Result<Self, Self::Error> {
         match &value.meta {
+            syn::Meta::Path(_) => SqcrabParams::parse.parse2(TokenStream::new()),
             syn::Meta::List(l) => SqcrabParams::parse.parse2(l.tokens.clone()),
             _ => Err(syn::Error::new(Span::call_site(), "Attribute format should be List")),
         }
     }
 }
 
+/// Parameters for `#[sqcrab_hint]`. With `class`, the methods in the impl block are registered as
+/// members of a native class for the type instead of as global functions.
+pub struct SqcrabHintParams {
+    class: bool,
+    domain: Option<String>
+}
+
+impl SqcrabHintParams {
+    fn get_domain(&self) -> String {
+        match &self.domain {
+            Some(n) => n.clone(),
+            None => SqcrabParams::DEFAULT_DOMAIN.to_string()
+        }
+    }
+}
+
+impl Parse for SqcrabHintParams {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let inputs = syn::punctuated::Punctuated::<syn::Meta, Token![,]>::parse_terminated(input)?;
+        let mut class = false;
+        let mut domain: Option<String> = None;
+        for input in inputs {
+            match input {
+                syn::Meta::Path(p) if p.is_ident("class") => class = true,
+                syn::Meta::NameValue(nv) if nv.path.is_ident("domain") => domain = Some(SqcrabParams::get_name_value(&nv.value)?),
+                _ => return Err(syn::Error::new(Span::call_site(), "Unknown sqcrab_hint parameter"))
+            }
+        }
+        Ok(Self { class, domain })
+    }
+}
+
+impl<'a> TryFrom<&'a syn::Attribute> for SqcrabHintParams {
+    type Error = syn::Error;
+
+    fn try_from(value: &'a syn::Attribute) -> Result<Self, Self::Error> {
+        match &value.meta {
+            syn::Meta::Path(_) => Ok(Self { class: false, domain: None }),
+            syn::Meta::List(l) => SqcrabHintParams::parse.parse2(l.tokens.clone()),
+            _ => Err(syn::Error::new(Span::call_site(), "Attribute format should be List")),
+        }
+    }
+}
+
 pub struct ItemFunction<'a> {
     // attribute: &'a syn::Attribute,
     attribute: SqcrabParams,
     this: Option<&'a syn::Type>,
+    // set for methods in a #[sqcrab_hint(class)] impl block
+    class: bool,
     visibility: &'a syn::Visibility,
     sig: &'a syn::Signature,
     block: &'a syn::Block
@@ -151,6 +202,7 @@ impl<'a> ItemFunction<'a> {
         Ok(Self {
             attribute: a.try_into()?,
             this: None,
+            class: false,
             visibility: &f.vis,
             sig: &f.sig,
             block: f.block.as_ref()
@@ -169,6 +221,7 @@ impl<'a> ItemFunction<'a> {
             // attribute,
             attribute: a.try_into()?,
             this: Some(t),
+            class: false,
             visibility: &f.vis,
             sig: &f.sig,
             block: &f.block
@@ -243,7 +296,8 @@ impl<'a> ItemFunction<'a> {
             syn::Type::Path(p) => {
                 match p.path.get_ident() {
                     Some(ident) => quote! { #ident },
-                    None => quote! { }
+                    // generic types such as Option<u32> are emitted as written
+                    None => quote! { #p }
                 }
             },
             syn::Type::Reference(p) => {
@@ -259,12 +313,87 @@ impl<'a> ItemFunction<'a> {
         }
     }
 
-    pub fn build(&self, sup: &DomainBuilderSupportItem) -> syn::Result<TokenStream> {
-        let args: Vec<&syn::FnArg> = self.sig.inputs.iter().collect();
-        let mut stack_idx = args.len();
+    // Gets T from a Result<T, E> return type
+    fn get_result_ok_type(ty: &syn::Type) -> Option<&syn::Type> {
+        let syn::Type::Path(p) = ty else { return None };
+        let segment = p.path.segments.last()?;
+        if segment.ident != "Result" { return None }
+        match &segment.arguments {
+            syn::PathArguments::AngleBracketed(args) => match args.args.first() {
+                Some(syn::GenericArgument::Type(t)) => Some(t),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // A parameter of type Args takes any remaining arguments
+    fn is_args_type(ty: &syn::Type) -> bool {
+        let syn::Type::Path(p) = ty else { return false };
+        p.path.segments.last().is_some_and(|s| s.ident == "Args")
+    }
+
+    // Reads a parameter, throwing an error to the script if it can't be converted
+    fn build_param_decl(&self, param_name: &syn::Ident, arg_name: &str, get: TokenStream) -> TokenStream {
+        let sq_name = self.get_squirrel_name();
+        quote! {
+            let #param_name = match #get {
+                Ok(v) => v,
+                Err(e) => return vm.throw_error(&format!("{}: invalid argument '{}': {}", #sq_name, #arg_name, e))
+            };
+        }
+    }
+
+    // Returns the number of parameters (including this) and the typemask expression for
+    // sq_setparamscheck. Masks for argument types come from CanSquirrel::type_mask.
+    fn build_params_check(&self, sup: &DomainBuilderSupportItem) -> syn::Result<(i64, TokenStream)> {
+        // this is the instance for class methods and constructors, otherwise it can be anything
+        let this_mask = match self.class && (self.attribute.constructor || self.sig.receiver().is_some()) {
+            true => "x",
+            false => "."
+        };
+        let mut masks = vec![quote! { #this_mask }];
+        let mut variadic = false;
+        for (i, arg) in self.sig.inputs.iter().enumerate() {
+            match arg {
+                syn::FnArg::Receiver(p) => if !self.class && self.attribute.local_pointer {
+                    let this_name = self.this.and_then(|t| Self::type_name_from_impl_block(t))
+                        .ok_or_else(|| syn::Error::new(Span::call_site(), "Impl declaration should have a name"))?;
+                    let ref_ty = Self::build_type_ref(p.reference.is_some(), p.mutability.is_some());
+                    let path = Self::build_struct_path(this_name, sup)?;
+                    masks.push(quote! { <#ref_ty #path #this_name as ::sqcrab::squirrel::type_cnv::CanSquirrel>::type_mask().as_str() });
+                },
+                syn::FnArg::Typed(p) if Self::is_args_type(p.ty.as_ref()) => {
+                    if i + 1 != self.sig.inputs.len() {
+                        return Err(syn::Error::new(Span::call_site(), format!(
+                            "{}: Args should be the last parameter", self.get_squirrel_name())));
+                    }
+                    variadic = true;
+                },
+                syn::FnArg::Typed(p) => {
+                    let name = Self::get_path_tokens(p.ty.as_ref());
+                    masks.push(quote! { <#name as ::sqcrab::squirrel::type_cnv::CanSquirrel>::type_mask().as_str() });
+                }
+            }
+        }
+        // variadic functions are called with at least the fixed parameters
+        let nparams = match variadic {
+            true => -(masks.len() as i64),
+            false => masks.len() as i64
+        };
+        Ok((nparams, quote! { &[#(#masks),*].concat() }))
+    }
+
+    // Builds the closure that reads parameters from the stack, calls the function and pushes the
+    // return value
+    fn build_closure(&self, sup: &DomainBuilderSupportItem) -> syn::Result<TokenStream> {
+        // arguments are read by their absolute position, where 1 is this and 2 is the first
+        // argument passed from the script
+        let mut stack_idx = 2usize;
         let mut param_decls = vec![];
-        for arg in &args {
-            let param_name = syn::Ident::new(&format!("p{}", stack_idx), Span::call_site());
+        let mut param_list = vec![];
+        for (i, arg) in self.sig.inputs.iter().enumerate() {
+            let param_name = syn::Ident::new(&format!("p{}", i + 1), Span::call_site());
             match arg {
                 syn::FnArg::Receiver(p) => {
                     // don't use receiver's ty since that will represent &Self instead of
@@ -276,70 +405,135 @@ impl<'a> ItemFunction<'a> {
                             let ref_ty = Self::build_type_ref(is_ref, is_mut);
                             // let path_tokens: TokenStream = path.parse()?;
                             let path = Self::build_struct_path(this_name, sup)?;
-                            // TODO: Type checking
-                            if self.attribute.local_pointer {
-                                param_decls.push(quote! {
-                                    let #param_name = vm.get::<#ref_ty #path #this_name>(#stack_idx).unwrap();
-                                });
+                            let get = if self.class {
+                                // the instance is this
+                                quote! { unsafe { vm.get_instance::<#path #this_name>(::sqcrab::squirrel::obj_type::StackIndex::Absolute(1)) } }
+                            } else if self.attribute.local_pointer {
+                                let get = quote! { vm.get::<#ref_ty #path #this_name>(::sqcrab::squirrel::obj_type::StackIndex::Absolute(#stack_idx)) };
+                                stack_idx += 1;
+                                get
                             } else {
-                                param_decls.push(quote! {
-                                    // let #param_name = vm.get_this::<#ref_ty #path #this_name>().unwrap();
-                                    let #param_name = unsafe { vm.get_this::<#path #this_name>().unwrap() };
-                                });
-                            }
+                                quote! { unsafe { vm.get_this::<#path #this_name>() } }
+                            };
+                            param_decls.push(self.build_param_decl(&param_name, "self", get));
+                            param_list.push(param_name);
                         }
                     }
                 },
+                syn::FnArg::Typed(p) if Self::is_args_type(p.ty.as_ref()) => {
+                    param_decls.push(quote! {
+                        let #param_name = ::sqcrab::squirrel::args::Args::starting_at(vm, #stack_idx);
+                    });
+                    param_list.push(param_name);
+                },
                 syn::FnArg::Typed(p) => {
-
                     let name = Self::get_path_tokens(p.ty.as_ref());
-                    param_decls.push(quote! {
-                        let #param_name = vm.get::<#name>(#stack_idx).unwrap();
-                    })
+                    let arg_name = p.pat.to_token_stream().to_string();
+                    param_decls.push(self.build_param_decl(&param_name, &arg_name,
+                        quote! { vm.get::<#name>(::sqcrab::squirrel::obj_type::StackIndex::Absolute(#stack_idx)) }));
+                    param_list.push(param_name);
+                    stack_idx += 1;
                 }
             }
-            stack_idx -= 1;
         }
         let method_call = self.build_method_call(sup)?;
-        let param_list: Vec<syn::Ident> = param_decls.iter().enumerate().map(|(i, _)|
-            syn::Ident::new(
-                &format!("p{}", param_decls.len() - i),
-                Span::call_site())).collect();
-        let sq_name = self.get_squirrel_name();
-        let params_returned: i64 = match &self.sig.output {
-            syn::ReturnType::Default => 0,
-            syn::ReturnType::Type(_, t) => {
-                match t.as_ref() {
+        // functions returning a Result throw the error to the script, and return the Ok value
+        let (output, fallible) = match &self.sig.output {
+            syn::ReturnType::Type(_, t) => match Self::get_result_ok_type(t.as_ref()) {
+                Some(ok) => (Some(ok), true),
+                None => (Some(t.as_ref()), false)
+            },
+            syn::ReturnType::Default => (None, false)
+        };
+        let params_returned: i64 = match output {
+            None => 0,
+            Some(t) => {
+                match t {
                     // note: tuple with no elements is void
                     syn::Type::Tuple(t) => (t.elems.iter().count() != 0).into(),
                     _ => 1
                 }
             }
         };
-        let ret_type = match &self.sig.output {
-            syn::ReturnType::Type(_, t) => {
-                Some(Self::get_path_tokens(t.as_ref()))
+        let ret_type = output.map(|t| Self::get_path_tokens(t));
+        let sq_name = self.get_squirrel_name();
+        let method_call = match fallible {
+            true => quote! {
+                match #method_call(#(#param_list),*) {
+                    Ok(v) => v,
+                    Err(e) => return vm.throw_error(&format!("{}: {}", #sq_name, e))
+                }
             },
-            _ => None
+            false => quote! { #method_call(#(#param_list),*) }
+        };
+        let params_returned: i64 = match self.attribute.constructor {
+            true => 0,
+            false => params_returned
         };
         let method_call = match params_returned {
-            0 => quote! { #method_call(#(#param_list),*); },
+            // constructors move the return value into the new instance
+            _ if self.attribute.constructor => quote! {
+                let ret = #method_call;
+                if let Err(e) = vm.set_instance(ret) {
+                    return vm.throw_error(&format!("{}: {}", #sq_name, e));
+                }
+            },
+            0 => quote! { #method_call; },
             _ => {
                 let ret_type = ret_type.unwrap();
                 quote! {
-                    let ret = #method_call(#(#param_list),*);
+                    let ret = #method_call;
                     vm.push::<#ret_type>(&ret);
                 }
             }
         };
         Ok(quote! {
-            vm.add_function(#sq_name, |vm| {
+            |vm| {
                 #(#param_decls)*
                 #method_call
                 #params_returned
-            })?;
+            }
         })
     }
+
+    pub fn build(&self, sup: &DomainBuilderSupportItem) -> syn::Result<TokenStream> {
+        let sq_name = self.get_squirrel_name();
+        let closure = self.build_closure(sup)?;
+        let (nparams, typemask) = self.build_params_check(sup)?;
+        Ok(quote! {
+            namespace.add_function_checked(vm, #sq_name, #nparams, #typemask, #closure)?;
+        })
+    }
+
+    /// Builds the call to add this function to a SqClassBuilder
+    pub fn build_member(&self, sup: &DomainBuilderSupportItem) -> syn::Result<TokenStream> {
+        let closure = self.build_closure(sup)?;
+        let sq_name = self.get_squirrel_name();
+        let (nparams, typemask) = self.build_params_check(sup)?;
+        let member = match self.attribute.constructor {
+            true => quote! { .constructor(#closure) },
+            // associated functions without a receiver are static members
+            false if self.sig.receiver().is_none() => quote! { .static_method(#sq_name, #closure) },
+            false => quote! { .method(#sq_name, #closure) }
+        };
+        Ok(quote! { #member.params_check(#nparams, #typemask) })
+    }
+}
+
+/// Builds the registration for a native class from a #[sqcrab_hint(class)] impl block
+fn build_class(ty: &syn::Type, members: &[ItemFunction], sup: &DomainBuilderSupportItem) -> syn::Result<TokenStream> {
+    let this_name = ItemFunction::type_name_from_impl_block(ty)
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "Impl declaration should have a name"))?;
+    let path = ItemFunction::build_struct_path(this_name, sup)?;
+    let mut member_tokens = vec![];
+    for member in members {
+        member_tokens.push(member.build_member(sup)?);
+    }
+    Ok(quote! {
+        vm.register_class::<#path #this_name>()
+            #(#member_tokens)*
+            .build_in(&namespace)?;
+    })
 }
 
 pub struct DomainBuilderSupportItem<'a> {
@@ -390,6 +584,16 @@ impl DomainBuilderConfig {
             .and_then(|a| a.as_array().map(|v|
                 v.iter().filter_map(|w| w.as_str()).collect()))
     }
+
+    /// Gets the table path that a domain's functions are registered into, set in the `namespaces`
+    /// table. Domains without a namespace use the root table.
+    pub fn get_namespace(&self, domain: &str) -> &str {
+        self.table.as_ref()
+            .and_then(|t| t.get("namespaces"))
+            .and_then(|n| n.get(domain))
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+    }
 }
 
 #[derive(Debug)]
@@ -457,11 +661,18 @@ impl DomainBuilder {
             for item in &tree.items {
                 match item {
                     syn::Item::Impl(v) => {
-                        if get_attribute_by_name(&v.attrs, SQCRAB_HINT_ATTR).is_some() {
-                            for func in v.items.iter()
+                        if let Some(a) = get_attribute_by_name(&v.attrs, SQCRAB_HINT_ATTR) {
+                            let hint: SqcrabHintParams = a.try_into()?;
+                            let funcs = v.items.iter()
                                 .filter_map(|i| filter_impl_by_sqcrab_methods(i, v.self_ty.as_ref())
-                                    .unwrap_or_else(|_| None)) {
-                                self.add_to_domain(func.get_domain(), func.build(&support_items)?);
+                                    .unwrap_or_else(|_| None));
+                            if hint.class {
+                                let members: Vec<_> = funcs.map(|mut f| { f.class = true; f }).collect();
+                                self.add_to_domain(hint.get_domain(), build_class(v.self_ty.as_ref(), &members, &support_items)?);
+                            } else {
+                                for func in funcs {
+                                    self.add_to_domain(func.get_domain(), func.build(&support_items)?);
+                                }
                             }
                         }
                     },
@@ -487,12 +698,16 @@ impl DomainBuilder {
 
         let mut domain_tokens = vec![];
         for (name, decl) in &self.domains {
+            let namespace = config.get_namespace(name);
             let name = syn::Ident::new(name, Span::call_site());
             domain_tokens.push(quote! {
                 pub struct #name;
 
                 impl ::sqcrab::domain::DomainRegistrar for #name {
+                    const NAMESPACE: &'static str = #namespace;
+
                     fn add_functions(vm: &mut ::sqcrab::squirrel::vm::SquirrelVM) -> Result<(), ::sqcrab::squirrel::err::SquirrelError> {
+                        let namespace = vm.namespace(Self::NAMESPACE)?;
                         #(#decl)*
                         Ok(())
                     }
@@ -1,12 +1,60 @@
 use proc_macro2::TokenStream;
-use quote::ToTokens;
+use quote::{quote, ToTokens};
 use syn::parse::{Parse, Parser };
 
-fn hint_inner(annotated_item: TokenStream) -> syn::Result<TokenStream> {
+fn is_class(input: TokenStream) -> syn::Result<bool> {
+    let mut class = false;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("class") {
+            class = true;
+            Ok(())
+        } else if meta.path.is_ident("domain") {
+            meta.value()?.parse::<syn::Expr>()?;
+            Ok(())
+        } else {
+            Err(meta.error("Unknown sqcrab_hint parameter"))
+        }
+    });
+    parser.parse2(input)?;
+    Ok(class)
+}
+
+// Implements SquirrelClass using the property accessors generated by #[derive(SqObject)]
+fn generate_squirrel_class_definition(item_impl: &syn::ItemImpl) -> syn::Result<TokenStream> {
+    let self_ty = &item_impl.self_ty;
+    let class_name = match &**self_ty {
+        syn::Type::Path(p) => p.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None
+    }.ok_or_else(|| syn::Error::new_spanned(self_ty, "Class impl should be for a named type"))?;
+    let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::sqcrab::squirrel::class::SquirrelClass for #self_ty #where_clause {
+            const CLASS_NAME: &'static str = #class_name;
+
+            fn get_property(&self, vm: &mut ::sqcrab::squirrel::vm::SquirrelVM, name: &str) -> bool {
+                self.__sqcrab_get_property(vm, name)
+            }
+
+            fn set_property(&mut self, vm: &::sqcrab::squirrel::vm::SquirrelVM, name: &str,
+                index: ::sqcrab::squirrel::obj_type::StackIndex) -> Result<bool, ::sqcrab::squirrel::err::SquirrelError> {
+                self.__sqcrab_set_property(vm, name, index)
+            }
+        }
+    })
+}
+
+fn hint_inner(input: TokenStream, annotated_item: TokenStream) -> syn::Result<TokenStream> {
     let item_impl = syn::ItemImpl::parse.parse2(annotated_item)?;
-    Ok(item_impl.to_token_stream())
+    let class = match is_class(input)? {
+        true => generate_squirrel_class_definition(&item_impl)?,
+        false => TokenStream::new()
+    };
+    Ok(quote! {
+        #item_impl
+        #class
+    })
 }
 
-pub fn hint(_: TokenStream, annotated_item: TokenStream) -> TokenStream {
-    hint_inner(annotated_item).unwrap_or_else(|e| e.to_compile_error())
-}
\ No newline at end of file
+pub fn hint(input: TokenStream, annotated_item: TokenStream) -> TokenStream {
+    hint_inner(input, annotated_item).unwrap_or_else(|e| e.to_compile_error())
+}
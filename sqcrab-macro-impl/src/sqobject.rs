@@ -12,29 +12,98 @@ fn generate_squirrel_type_definition(name: &str, mutable: bool) -> TokenStream {
         false => quote! { &'a }
     };
     let name = syn::Ident::new(name, Span::call_site());
+    let from_ptr = match mutable {
+        true => quote! { unsafe { &mut *v.as_ptr() } },
+        false => quote! { unsafe { &*v.as_ptr() } }
+    };
+    // the pointer is pushed with #name's type tag, so it's checked before being converted back
     quote! {
         impl<'a> ::sqcrab::squirrel::type_cnv::CanSquirrel for #ref_type #name {
-            type Into = ::sqcrab::squirrel::obj_type::UserPointer<Self>;
+            type Into = ::sqcrab::squirrel::obj_type::UserPointer<#name>;
             const RETURNS: bool = true;
 
             fn into_squirrel(&self) -> Self::Into {
-                ::sqcrab::squirrel::obj_type::UserPointer::<Self>::new(self)
+                ::sqcrab::squirrel::obj_type::UserPointer::<#name>::new(&**self)
             }
 
             fn from_squirrel(v: Self::Into) -> Self {
-                unsafe { *v.as_ptr() }
+                #from_ptr
+            }
+
+            // references can only be read from userdata pushed as a UserPointer
+            fn type_mask() -> String {
+                "u".to_string()
             }
         }
     }
 }
 
+fn is_property(field: &syn::Field) -> syn::Result<bool> {
+    let mut property = false;
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("sqobject")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("property") {
+                property = true;
+                Ok(())
+            } else {
+                Err(meta.error("Unknown sqobject parameter"))
+            }
+        })?;
+    }
+    Ok(property)
+}
+
+// The property accessors used by SquirrelClass. The trait itself is implemented by
+// #[sqcrab_hint(class)], since only types registered as classes need to be Send and 'static.
+fn generate_property_accessors(target_struct: &syn::ItemStruct) -> syn::Result<TokenStream> {
+    let name = &target_struct.ident;
+    let (impl_generics, ty_generics, where_clause) = target_struct.generics.split_for_impl();
+    let mut properties = vec![];
+    for field in &target_struct.fields {
+        if let Some(ident) = &field.ident && is_property(field)? {
+            properties.push((ident, ident.to_string()));
+        }
+    }
+    let get_arms = properties.iter().map(|(ident, key)| quote! {
+        #key => { vm.push(&self.#ident); true },
+    });
+    let set_arms = properties.iter().map(|(ident, key)| quote! {
+        #key => { self.#ident = vm.get(index)?; Ok(true) },
+    });
+    Ok(quote! {
+        #[allow(dead_code)]
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc(hidden)]
+            #[allow(unused_variables)]
+            pub fn __sqcrab_get_property(&self, vm: &mut ::sqcrab::squirrel::vm::SquirrelVM, name: &str) -> bool {
+                match name {
+                    #(#get_arms)*
+                    _ => false
+                }
+            }
+
+            #[doc(hidden)]
+            #[allow(unused_variables)]
+            pub fn __sqcrab_set_property(&mut self, vm: &::sqcrab::squirrel::vm::SquirrelVM, name: &str,
+                index: ::sqcrab::squirrel::obj_type::StackIndex) -> Result<bool, ::sqcrab::squirrel::err::SquirrelError> {
+                match name {
+                    #(#set_arms)*
+                    _ => Ok(false)
+                }
+            }
+        }
+    })
+}
+
 fn sqobject_inner(item: TokenStream) -> syn::Result<TokenStream> {
     let target_struct = syn::ItemStruct::parse.parse2(item)?;
     let struct_name = target_struct.ident.to_string();
     let def = generate_squirrel_type_definition(&struct_name, false);
     let def_mut = generate_squirrel_type_definition(&struct_name, true);
+    let properties = generate_property_accessors(&target_struct)?;
     Ok(quote! {
         #def
         #def_mut
+        #properties
     })
 }
\ No newline at end of file
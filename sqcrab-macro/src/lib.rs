@@ -5,7 +5,7 @@ pub fn sqcrab(input: TokenStream, annotated_item: TokenStream) -> TokenStream {
     sqcrab_macro_impl::sqcrab::sqcrab(input.into(), annotated_item.into()).into()
 }
 
-#[proc_macro_derive(SqObject)]
+#[proc_macro_derive(SqObject, attributes(sqobject))]
 pub fn derive_sq_object(item: TokenStream) -> TokenStream {
     sqcrab_macro_impl::sqobject::sqobject(item.into()).into()
 }
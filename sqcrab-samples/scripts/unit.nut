@@ -3,4 +3,17 @@ function replenish_unit() {
     this.unit_set_hp(current_hp + 25);
     local current_mp = this.unit_get_mp();
     this.unit_set_mp(current_mp + 10);
+}
+
+function spawn_unit() {
+    local unit = Unit(2);
+    unit.hp = Unit.default_hp() + 25;
+    unit.take_damage(30);
+    unit.take_hits(5, 10);
+    unit.heal(5);
+    local fallen = Unit(3);
+    fallen.hp = 0;
+    try { fallen.heal(5); } catch (e) { print(e); }
+    print("Spawned unit " + unit.id + " with " + unit.hp + " HP (alive: " + unit.is_alive() + ")");
+    return unit.hp;
 }
\ No newline at end of file
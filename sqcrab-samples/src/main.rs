@@ -22,6 +22,8 @@ fn execute() -> Result<(), Box<dyn Error>> {
         println!("Curr: {:?}", unit);
         squirrel!(script replenish_unit())?;
         println!("After script: {:?}", unit);
+        let hp = squirrel!(script spawn_unit() -> u32)?;
+        println!("Spawned unit has {} HP", hp);
         Ok(())
     })?;
     Ok(())
@@ -1 +1 @@
-pub struct Test ; impl :: sqcrab :: domain :: DomainRegistrar for Test { fn add_functions (vm : & mut :: sqcrab :: squirrel :: vm :: SquirrelVM) -> Result < () , :: sqcrab :: squirrel :: err :: SquirrelError > { vm . add_function ("unit_get_hp" , | vm | { let p1 = unsafe { vm . get_this :: < crate :: unit :: Unit > () . unwrap () } ; let ret = crate :: unit :: Unit :: get_hp (p1) ; vm . push :: < u32 > (& ret) ; 1i64 }) ? ; vm . add_function ("unit_set_hp" , | vm | { let p2 = unsafe { vm . get_this :: < crate :: unit :: Unit > () . unwrap () } ; let p1 = vm . get :: < u32 > (1usize) . unwrap () ; crate :: unit :: Unit :: set_hp (p2 , p1) ; 0i64 }) ? ; vm . add_function ("unit_get_mp" , | vm | { let p1 = unsafe { vm . get_this :: < crate :: unit :: Unit > () . unwrap () } ; let ret = crate :: unit :: Unit :: get_mp (p1) ; vm . push :: < u32 > (& ret) ; 1i64 }) ? ; vm . add_function ("unit_set_mp" , | vm | { let p2 = unsafe { vm . get_this :: < crate :: unit :: Unit > () . unwrap () } ; let p1 = vm . get :: < u32 > (1usize) . unwrap () ; crate :: unit :: Unit :: set_mp (p2 , p1) ; 0i64 }) ? ; Ok (()) } }
\ No newline at end of file
+pub struct Test ; impl :: sqcrab :: domain :: DomainRegistrar for Test { const NAMESPACE : & 'static str = "" ; fn add_functions (vm : & mut :: sqcrab :: squirrel :: vm :: SquirrelVM) -> Result < () , :: sqcrab :: squirrel :: err :: SquirrelError > { let namespace = vm . namespace (Self :: NAMESPACE) ? ; namespace . add_function_checked (vm , "unit_get_hp" , 1i64 , & ["."] . concat () , | vm | { let p1 = match unsafe { vm . get_this :: < crate :: unit :: Unit > () } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_get_hp" , "self" , e)) } ; let ret = crate :: unit :: Unit :: get_hp (p1) ; vm . push :: < u32 > (& ret) ; 1i64 }) ? ; namespace . add_function_checked (vm , "unit_set_hp" , 2i64 , & ["." , < u32 as :: sqcrab :: squirrel :: type_cnv :: CanSquirrel > :: type_mask () . as_str ()] . concat () , | vm | { let p1 = match unsafe { vm . get_this :: < crate :: unit :: Unit > () } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_set_hp" , "self" , e)) } ; let p2 = match vm . get :: < u32 > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (2usize)) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_set_hp" , "v" , e)) } ; crate :: unit :: Unit :: set_hp (p1 , p2) ; 0i64 }) ? ; namespace . add_function_checked (vm , "unit_get_mp" , 1i64 , & ["."] . concat () , | vm | { let p1 = match unsafe { vm . get_this :: < crate :: unit :: Unit > () } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_get_mp" , "self" , e)) } ; let ret = crate :: unit :: Unit :: get_mp (p1) ; vm . push :: < u32 > (& ret) ; 1i64 }) ? ; namespace . add_function_checked (vm , "unit_set_mp" , 2i64 , & ["." , < u32 as :: sqcrab :: squirrel :: type_cnv :: CanSquirrel > :: type_mask () . as_str ()] . concat () , | vm | { let p1 = match unsafe { vm . get_this :: < crate :: unit :: Unit > () } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_set_mp" , "self" , e)) } ; let p2 = match vm . get :: < u32 > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (2usize)) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "unit_set_mp" , "v" , e)) } ; crate :: unit :: Unit :: set_mp (p1 , p2) ; 0i64 }) ? ; vm . register_class :: < crate :: unit :: Unit > () . constructor (| vm | { let p1 = match vm . get :: < u32 > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (2usize)) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "new" , "id" , e)) } ; let ret = crate :: unit :: Unit :: new (p1) ; if let Err (e) = vm . set_instance (ret) { return vm . throw_error (& format ! ("{}: {}" , "new" , e)) ; } 0i64 }) . params_check (2i64 , & ["x" , < u32 as :: sqcrab :: squirrel :: type_cnv :: CanSquirrel > :: type_mask () . as_str ()] . concat ()) . method ("take_damage" , | vm | { let p1 = match unsafe { vm . get_instance :: < crate :: unit :: Unit > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (1)) } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "take_damage" , "self" , e)) } ; let p2 = match vm . get :: < u32 > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (2usize)) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "take_damage" , "v" , e)) } ; crate :: unit :: Unit :: take_damage (p1 , p2) ; 0i64 }) . params_check (2i64 , & ["x" , < u32 as :: sqcrab :: squirrel :: type_cnv :: CanSquirrel > :: type_mask () . as_str ()] . concat ()) . method ("is_alive" , | vm | { let p1 = match unsafe { vm . get_instance :: < crate :: unit :: Unit > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (1)) } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "is_alive" , "self" , e)) } ; let ret = crate :: unit :: Unit :: is_alive (p1) ; vm . push :: < bool > (& ret) ; 1i64 }) . params_check (1i64 , & ["x"] . concat ()) . method ("heal" , | vm | { let p1 = match unsafe { vm . get_instance :: < crate :: unit :: Unit > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (1)) } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "heal" , "self" , e)) } ; let p2 = match vm . get :: < u32 > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (2usize)) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "heal" , "v" , e)) } ; let ret = match crate :: unit :: Unit :: heal (p1 , p2) { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: {}" , "heal" , e)) } ; vm . push :: < u32 > (& ret) ; 1i64 }) . params_check (2i64 , & ["x" , < u32 as :: sqcrab :: squirrel :: type_cnv :: CanSquirrel > :: type_mask () . as_str ()] . concat ()) . method ("take_hits" , | vm | { let p1 = match unsafe { vm . get_instance :: < crate :: unit :: Unit > (:: sqcrab :: squirrel :: obj_type :: StackIndex :: Absolute (1)) } { Ok (v) => v , Err (e) => return vm . throw_error (& format ! ("{}: invalid argument '{}': {}" , "take_hits" , "self" , e)) } ; let p2 = :: sqcrab :: squirrel :: args :: Args :: starting_at (vm , 2usize) ; let ret = crate :: unit :: Unit :: take_hits (p1 , p2) ; vm . push :: < u32 > (& ret) ; 1i64 }) . params_check (- 1i64 , & ["x"] . concat ()) . static_method ("default_hp" , | vm | { let ret = crate :: unit :: Unit :: default_hp () ; vm . push :: < u32 > (& ret) ; 1i64 }) . params_check (1i64 , & ["."] . concat ()) . build_in (& namespace) ? ; Ok (()) } }
\ No newline at end of file
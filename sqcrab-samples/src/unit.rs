@@ -1,9 +1,14 @@
+use sqcrab::squirrel::args::Args;
+use sqcrab::squirrel::value::SqValue;
 use sqcrab_macro::{sqcrab, sqcrab_hint, SqObject};
 
 #[derive(Debug, SqObject)]
 pub struct Unit {
+    #[sqobject(property)]
     id: u32,
+    #[sqobject(property)]
     hp: u32,
+    #[sqobject(property)]
     mp: u32
 }
 
@@ -27,4 +32,32 @@ impl Unit {
     pub fn get_mp(&self) -> u32 { self.mp }
     #[sqcrab(name = "unit_set_mp", domain = "Test")]
     pub fn set_mp(&mut self, v: u32) { self.mp = v }
-}
\ No newline at end of file
+}
+
+#[sqcrab_hint(class, domain = "Test")]
+impl Unit {
+    #[sqcrab(constructor)]
+    pub fn new(id: u32) -> Self { Self { id, ..Default::default() } }
+    #[sqcrab]
+    pub fn take_damage(&mut self, v: u32) { self.hp = self.hp.saturating_sub(v) }
+    #[sqcrab]
+    pub fn is_alive(&self) -> bool { self.hp > 0 }
+    #[sqcrab]
+    pub fn heal(&mut self, v: u32) -> Result<u32, String> {
+        match self.hp {
+            0 => Err(format!("unit {} can't be healed", self.id)),
+            _ => { self.hp += v; Ok(self.hp) }
+        }
+    }
+    #[sqcrab]
+    pub fn take_hits(&mut self, hits: Args) -> u32 {
+        for hit in hits.iter().flatten() {
+            if let SqValue::Integer(v) = hit {
+                self.take_damage(v as u32);
+            }
+        }
+        self.hp
+    }
+    #[sqcrab]
+    pub fn default_hp() -> u32 { Self::default().hp }
+}
@@ -12,7 +12,7 @@ use crate::domain::DomainRegistrar;
 
 #[derive(Debug)]
 pub struct SqCrabBuilder<'a, T>
-where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+where &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     debug_flags: DebuggerFlags,
     inner: SquirrelVMBuilder,
@@ -20,7 +20,7 @@ where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
 }
 
 impl<'a, T> Deref for SqCrabBuilder<'a, T>
-where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+where &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     type Target = SquirrelVMBuilder;
 
@@ -30,7 +30,7 @@ where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
 }
 
 impl<'a, T> DerefMut for SqCrabBuilder<'a, T>
-where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+where &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.inner
@@ -38,17 +38,14 @@ where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
 }
 
 impl<'a, T> SqCrabBuilder<'a, T>
-where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+where &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     fn new(debug_flags: DebuggerFlags) -> Self {
         Self { debug_flags, inner: SquirrelVM::new(), _this: PhantomData::<&'a T> }
     }
 
     pub fn build(self) -> SqCrab<'a, CrabDebugger, T> {
-        let mut vm = Box::new(self.inner.build());
-        if unsafe { !squirrel::vm::check_squirrel_handle(vm.as_ref()) } {
-            unsafe { squirrel::vm::add_squirrel_handle(vm.as_mut()) };
-        }
+        let vm = Box::new(self.inner.build());
         SqCrab::<'a, CrabDebugger, T>::from_parts(vm, CrabDebugger::new(DebuggerFlags::default()))
     }
 }
@@ -56,7 +53,7 @@ where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
 #[derive(Debug)]
 pub struct SqCrab<'a, D, T>
 where D: ScriptDebugger,
-      &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+      &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     debugger: D,
     sqvm: Box<SquirrelVM>,
@@ -65,14 +62,14 @@ where D: ScriptDebugger,
 
 impl<'a, D, T> Drop for SqCrab<'a, D, T>
 where D: ScriptDebugger,
-      &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+      &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     fn drop(&mut self) {
     }
 }
 
 impl<'a, T> SqCrab<'a, CrabDebugger, T>
-where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+where &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     pub fn new() -> SqCrabBuilder<'a, T> {
         SqCrabBuilder::<T>::new(DebuggerFlags::default())
@@ -81,7 +78,7 @@ where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
 
 impl<'a, D, T> SqCrab<'a, D, T>
 where D: ScriptDebugger,
-      &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+      &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     pub fn from_parts(sqvm: Box<SquirrelVM>, debugger: D) -> Self {
         Self { debugger, sqvm, _this: PhantomData::<&'a T> }
@@ -107,7 +104,7 @@ where D: ScriptDebugger,
 
 impl<'a, D, T> Deref for SqCrab<'a, D, T>
 where D: ScriptDebugger,
-      &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+      &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     type Target = SquirrelVM;
 
@@ -118,7 +115,7 @@ where D: ScriptDebugger,
 
 impl<'a, D, T> DerefMut for SqCrab<'a, D, T>
 where D: ScriptDebugger,
-      &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>>
+      &'a mut T: CanSquirrel<Into = UserPointer<T>>
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.sqvm.as_mut()
@@ -2,5 +2,9 @@ use squirrel::err::SquirrelError;
 use squirrel::vm::SquirrelVM;
 
 pub trait DomainRegistrar {
+    /// Path of the table that the functions are added to, e.g `"Battle.Unit"`. Functions are added
+    /// to the root table if this is empty.
+    const NAMESPACE: &'static str = "";
+
     fn add_functions(vm: &mut SquirrelVM) -> Result<(), SquirrelError>;
-}
\ No newline at end of file
+}
@@ -0,0 +1,100 @@
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::StackIndex;
+use crate::type_cnv::CanSquirrel;
+use crate::value::SqValue;
+use crate::vm::SquirrelVM;
+
+/// The arguments passed to a native function, for functions that take a variable number of
+/// parameters (e.g `log(...)`). Arguments are indexed from 0, which is the first argument after
+/// `this`.
+///
+/// Arguments can be read as a type with [`Args::get`], or iterated over as [`SqValue`]s when
+/// their types aren't known ahead of time.
+///
+/// `#[sqcrab]` functions can take `args: Args` as their last parameter, which holds any arguments
+/// after the fixed parameters.
+#[derive(Clone, Copy)]
+pub struct Args<'a> {
+    vm: &'a SquirrelVM,
+    // absolute stack index of the first argument
+    first: usize,
+    len: usize
+}
+
+impl<'a> Args<'a> {
+    /// All of the arguments passed to the function, not including `this`
+    pub fn new(vm: &'a SquirrelVM) -> Self {
+        Self::starting_at(vm, 2)
+    }
+
+    /// The arguments from the given absolute stack index, where 2 is the first argument. This is
+    /// used when a function has fixed parameters before the variadic ones.
+    pub fn starting_at(vm: &'a SquirrelVM, first: usize) -> Self {
+        let top = unsafe { sq_gettop(vm.handle) } as usize;
+        Self { vm, first, len: (top + 1).saturating_sub(first) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the argument at the given index as T
+    pub fn get<T: CanSquirrel>(&self, index: usize) -> Result<T, SquirrelError> {
+        match index < self.len {
+            true => self.vm.get::<T>(StackIndex::Absolute(self.first + index)),
+            false => Err(SquirrelError::IndexOutOfRange(index))
+        }
+    }
+
+    /// Iterates over the arguments as dynamic values
+    pub fn iter(&self) -> ArgsIter<'a> {
+        ArgsIter { args: *self, pos: 0 }
+    }
+}
+
+impl<'a> IntoIterator for Args<'a> {
+    type Item = Result<SqValue, SquirrelError>;
+    type IntoIter = ArgsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &Args<'a> {
+    type Item = Result<SqValue, SquirrelError>;
+    type IntoIter = ArgsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the arguments of a variadic function, created from [`Args::iter`]
+pub struct ArgsIter<'a> {
+    args: Args<'a>,
+    pos: usize
+}
+
+impl<'a> Iterator for ArgsIter<'a> {
+    type Item = Result<SqValue, SquirrelError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.args.len { return None }
+        let index = StackIndex::Absolute(self.args.first + self.pos);
+        self.pos += 1;
+        Some(self.args.vm.get::<SqValue>(index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.args.len - self.pos;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for ArgsIter<'a> {}
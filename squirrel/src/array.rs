@@ -0,0 +1,211 @@
+use std::ops::Deref;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::{SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::table::SqTableIter;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::SquirrelVM;
+
+/// Pushes a new array containing each element of the slice
+pub(crate) fn push_slice<T: CanSquirrel>(vm: &mut SquirrelVM, values: &[T]) {
+    unsafe { sq_newarray(vm.handle, 0) };
+    for value in values {
+        vm.push(value);
+        unsafe { sq_arrayappend(vm.handle, -2) };
+    }
+}
+
+/// Reads each element of the array at the given stack index
+pub(crate) fn get_vec<T: CanSquirrel>(vm: &SquirrelVM, index: StackIndex) -> Result<Vec<T>, SquirrelError> {
+    <SqArray as SquirrelObject>::get(vm, index)?.iter::<T>(vm).collect()
+}
+
+/// A handle to a Squirrel array
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SqArray(SqObjectRef);
+
+impl SqArray {
+    /// Creates a new array containing `size` null elements
+    pub fn new(vm: &SquirrelVM, size: usize) -> Self {
+        let _check = vm.stack_check();
+        unsafe { sq_newarray(vm.handle, size as _) };
+        Self(SqObjectRef::take_top(vm))
+    }
+
+    pub fn append<T>(&self, vm: &mut SquirrelVM, value: &T) -> Result<(), SquirrelError>
+    where T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(value);
+        let res = unsafe { sq_arrayappend(vm.handle, -2) };
+        match res {
+            0 => Ok(()),
+            _ => Err(SquirrelError::CouldNotAppend)
+        }
+    }
+
+    /// Removes the last element from the array and returns it
+    pub fn pop<T>(&self, vm: &SquirrelVM) -> Result<T, SquirrelError>
+    where T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        match unsafe { sq_arraypop(vm.handle, -1, true.into()) } {
+            0 => vm.get::<T>(1),
+            _ => Err(SquirrelError::ArrayEmpty)
+        }
+    }
+
+    /// Resizes the array. New elements are set to null.
+    pub fn resize(&self, vm: &SquirrelVM, size: usize) -> Result<(), SquirrelError> {
+        let _check = vm.stack_check();
+        unsafe {
+            self.0.push_raw(vm);
+            let res = sq_arrayresize(vm.handle, -1, size as _);
+            sq_poptop(vm.handle);
+            match res {
+                0 => Ok(()),
+                _ => Err(SquirrelError::IndexOutOfRange(size))
+            }
+        }
+    }
+
+    /// Inserts a value at the given position, shifting all elements after it to the right
+    pub fn insert<T>(&self, vm: &mut SquirrelVM, index: usize, value: &T) -> Result<(), SquirrelError>
+    where T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(value);
+        let res = unsafe { sq_arrayinsert(vm.handle, -2, index as _) };
+        match res {
+            0 => Ok(()),
+            _ => Err(SquirrelError::IndexOutOfRange(index))
+        }
+    }
+
+    /// Removes the element at the given position, shifting all elements after it to the left
+    pub fn remove(&self, vm: &SquirrelVM, index: usize) -> Result<(), SquirrelError> {
+        let _check = vm.stack_check();
+        unsafe {
+            self.0.push_raw(vm);
+            let res = sq_arrayremove(vm.handle, -1, index as _);
+            sq_poptop(vm.handle);
+            match res {
+                0 => Ok(()),
+                _ => Err(SquirrelError::IndexOutOfRange(index))
+            }
+        }
+    }
+
+    pub fn get<T>(&self, vm: &SquirrelVM, index: usize) -> Result<T, SquirrelError>
+    where T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe {
+            self.0.push_raw(vm);
+            sq_pushinteger(vm.handle, index as _);
+        }
+        match unsafe { sq_get(vm.handle, -2) } {
+            0 => vm.get::<T>(1),
+            _ => Err(SquirrelError::IndexOutOfRange(index))
+        }
+    }
+
+    pub fn set<T>(&self, vm: &mut SquirrelVM, index: usize, value: &T) -> Result<(), SquirrelError>
+    where T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe {
+            self.0.push_raw(vm);
+            sq_pushinteger(vm.handle, index as _);
+        }
+        vm.push(value);
+        let res = unsafe { sq_set(vm.handle, -3) };
+        match res {
+            0 => Ok(()),
+            _ => Err(SquirrelError::IndexOutOfRange(index))
+        }
+    }
+
+    pub fn len(&self, vm: &SquirrelVM) -> usize {
+        let _check = vm.stack_check();
+        unsafe {
+            self.0.push_raw(vm);
+            let size = sq_getsize(vm.handle, -1);
+            sq_poptop(vm.handle);
+            size as usize
+        }
+    }
+
+    pub fn is_empty(&self, vm: &SquirrelVM) -> bool {
+        self.len(vm) == 0
+    }
+
+    /// Iterates through each element in the array, converting them into T
+    pub fn iter<'a, T>(&self, vm: &'a SquirrelVM) -> impl Iterator<Item = Result<T, SquirrelError>> + 'a
+    where T: CanSquirrel + 'a {
+        SqTableIter::<SQInteger, T>::new(vm, &self.0).map(|v| v.map(|(_, value)| value))
+    }
+}
+
+impl Deref for SqArray {
+    type Target = SqObjectRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<SqArray> for SqObjectRef {
+    fn from(value: SqArray) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<SqObjectRef> for SqArray {
+    type Error = SquirrelError;
+
+    fn try_from(value: SqObjectRef) -> Result<Self, Self::Error> {
+        match value.is::<Self>() {
+            true => Ok(Self(value)),
+            false => Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
+    }
+}
+
+impl SquirrelObject for SqArray {
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.0.push_raw(vm) };
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        <SqObjectRef as SquirrelObject>::get(vm, index)?.try_into().map_err(|_| SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl SquirrelTypeId for SqArray {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_ARRAY as _
+    }
+}
+
+impl CanSquirrel for SqArray {
+    type Into = SqArray;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "a".to_string()
+    }
+}
@@ -0,0 +1,281 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::{stack_type_name, type_tag, SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::table::SqTable;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::{SquirrelFunction, SquirrelVM};
+
+/// A Rust type that can be bound as a native Squirrel class. Instances created from a script own
+/// a boxed T, which is dropped when the instance is released, so T needs to be `Send` for the VM
+/// to be moved to another thread. This is implemented by `#[sqcrab_hint(class)]` on an impl block
+/// of an `SqObject` type, where fields marked with `#[sqobject(property)]` are exposed as
+/// properties.
+pub trait SquirrelClass: Sized + Send + 'static {
+    const CLASS_NAME: &'static str;
+
+    /// Pushes the value of the property with the given name. Returns false if there's no property
+    /// with that name.
+    fn get_property(&self, vm: &mut SquirrelVM, name: &str) -> bool;
+
+    /// Sets the property with the given name from the value at the given stack index. Returns
+    /// false if there's no property with that name.
+    fn set_property(&mut self, vm: &SquirrelVM, name: &str, index: StackIndex) -> Result<bool, SquirrelError>;
+}
+
+unsafe extern "C" fn release_instance<T>(p: SQUserPointer, _: SQInteger) -> SQInteger {
+    if !p.is_null() {
+        drop(unsafe { Box::from_raw(p as *mut T) });
+    }
+    1
+}
+
+// Moves the value into the instance at the given stack index
+pub(crate) unsafe fn set_instance_up<T>(vm: &mut SquirrelVM, index: SQInteger, value: T) -> Result<(), SquirrelError>
+where T: SquirrelClass {
+    let mut ptr: SQUserPointer = std::ptr::null_mut();
+    let res = unsafe { sq_getinstanceup(vm.handle, index, &mut ptr, type_tag::<T>(), false.into()) };
+    if res != 0 {
+        return Err(SquirrelError::TypeTagMismatch {
+            expected: std::any::type_name::<T>().to_string(),
+            found: stack_type_name(vm.handle, index)
+        });
+    }
+    unsafe {
+        // the constructor was called again, drop the previous value
+        if !ptr.is_null() {
+            release_instance::<T>(ptr, 0);
+        }
+        sq_setinstanceup(vm.handle, index, Box::into_raw(Box::new(value)) as _);
+        sq_setreleasehook(vm.handle, index, Some(release_instance::<T>));
+    }
+    Ok(())
+}
+
+// Throwing null from _get or _set tells the VM that the slot doesn't exist, rather than raising an
+// error
+fn throw_not_found(vm: &mut SquirrelVM) -> SQInteger {
+    unsafe {
+        sq_pushnull(vm.handle);
+        sq_throwobject(vm.handle)
+    }
+}
+
+// _get(key), called with the instance as this
+fn class_get<T: SquirrelClass>(vm: &mut SquirrelVM) -> SQInteger {
+    let key = match vm.get::<String>(StackIndex::Absolute(2)) {
+        Ok(k) => k,
+        Err(_) => return throw_not_found(vm)
+    };
+    match unsafe { vm.get_instance::<T>(StackIndex::Absolute(1)) } {
+        Ok(this) if this.get_property(vm, &key) => 1,
+        _ => throw_not_found(vm)
+    }
+}
+
+// _set(key, value), called with the instance as this
+fn class_set<T: SquirrelClass>(vm: &mut SquirrelVM) -> SQInteger {
+    let key = match vm.get::<String>(StackIndex::Absolute(2)) {
+        Ok(k) => k,
+        Err(_) => return throw_not_found(vm)
+    };
+    let this = match unsafe { vm.get_instance::<T>(StackIndex::Absolute(1)) } {
+        Ok(this) => this,
+        Err(e) => return vm.throw_error(&format!("{}: {}", T::CLASS_NAME, e))
+    };
+    match this.set_property(vm, &key, StackIndex::Absolute(3)) {
+        Ok(true) => 0,
+        Ok(false) => throw_not_found(vm),
+        Err(e) => vm.throw_error(&format!("could not set {}.{}: {}", T::CLASS_NAME, key, e))
+    }
+}
+
+struct ClassMember {
+    name: String,
+    func: SquirrelFunction,
+    is_static: bool,
+    params_check: Option<(SQInteger, String)>
+}
+
+/// Builder for registering a native class, created from [`SquirrelVM::register_class`]
+pub struct SqClassBuilder<'a, T> {
+    vm: &'a mut SquirrelVM,
+    members: Vec<ClassMember>,
+    // returned from build, since the builder methods can't fail
+    error: Option<SquirrelError>,
+    _type: PhantomData<T>
+}
+
+impl<'a, T> SqClassBuilder<'a, T>
+where T: SquirrelClass {
+    pub(crate) fn new(vm: &'a mut SquirrelVM) -> Self {
+        Self { vm, members: vec![], error: None, _type: PhantomData }
+    }
+
+    fn add_member(mut self, name: &str, func: SquirrelFunction, is_static: bool) -> Self {
+        self.members.push(ClassMember { name: name.to_owned(), func, is_static, params_check: None });
+        self
+    }
+
+    /// Sets the function that's called for `ClassName(...)`. This should create a T from the
+    /// arguments and move it into the instance using [`SquirrelVM::set_instance`].
+    pub fn constructor<F>(self, func: F) -> Self
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        self.add_member("constructor", Box::new(func), false)
+    }
+
+    /// Adds a method to the class. The instance is passed as `this`, which can be read with
+    /// [`SquirrelVM::get_instance`].
+    pub fn method<F>(self, name: &str, func: F) -> Self
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        self.add_member(name, Box::new(func), false)
+    }
+
+    /// Adds a static member function to the class, which can be called without an instance (e.g
+    /// `ClassName.function()`).
+    pub fn static_method<F>(self, name: &str, func: F) -> Self
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        self.add_member(name, Box::new(func), true)
+    }
+
+    /// Sets the number of parameters and the typemask that are checked before the last added
+    /// constructor or method is called, see [`SqTable::add_function_checked`]. Building the class
+    /// fails if no member has been added yet.
+    pub fn params_check(mut self, nparams: SQInteger, typemask: &str) -> Self {
+        match self.members.last_mut() {
+            Some(member) => member.params_check = Some((nparams, typemask.to_owned())),
+            None => { self.error.get_or_insert(SquirrelError::ParamsCheckWithoutMember); }
+        }
+        self
+    }
+
+    fn new_member(&mut self, member: ClassMember) -> Result<(), SquirrelError> {
+        let ClassMember { name, func, is_static, params_check } = member;
+        unsafe { sq_pushstring(self.vm.handle, name.as_ptr() as _, name.len() as _) };
+        self.vm.push_native_closure(&format!("{}.{}", T::CLASS_NAME, name), func,
+            params_check.as_ref().map(|(n, m)| (*n, m.as_str())))?;
+        match unsafe { sq_newslot(self.vm.handle, -3, is_static.into()) } {
+            0 => Ok(()),
+            _ => Err(SquirrelError::CouldNotSetSlot(name))
+        }
+    }
+
+    fn build_inner(&mut self, table: &SqTable) -> Result<SqClass, SquirrelError> {
+        let handle = self.vm.handle;
+        unsafe {
+            table.push_raw(self.vm);
+            sq_pushstring(handle, T::CLASS_NAME.as_ptr() as _, T::CLASS_NAME.len() as _);
+            if sq_newclass(handle, false.into()) != 0 {
+                return Err(SquirrelError::CouldNotCreateClass(T::CLASS_NAME.to_owned()));
+            }
+            sq_settypetag(handle, -1, type_tag::<T>());
+        }
+        for member in std::mem::take(&mut self.members) {
+            self.new_member(member)?;
+        }
+        // _get(key) and _set(key, value)
+        self.new_member(ClassMember {
+            name: "_get".to_owned(), func: Box::new(class_get::<T>), is_static: false, params_check: Some((2, "x".to_owned()))
+        })?;
+        self.new_member(ClassMember {
+            name: "_set".to_owned(), func: Box::new(class_set::<T>), is_static: false, params_check: Some((3, "x".to_owned()))
+        })?;
+        let class = SqClass(<SqObjectRef as SquirrelObject>::get(self.vm, 1.into())?);
+        match unsafe { sq_newslot(handle, -3, false.into()) } {
+            0 => Ok(class),
+            _ => Err(SquirrelError::CouldNotSetSlot(T::CLASS_NAME.to_owned()))
+        }
+    }
+
+    /// Creates the class and adds it to the root table
+    pub fn build(self) -> Result<SqClass, SquirrelError> {
+        let root = SqTable::root(self.vm);
+        self.build_in(&root)
+    }
+
+    /// Creates the class and adds it to the given table
+    pub fn build_in(mut self, table: &SqTable) -> Result<SqClass, SquirrelError> {
+        if let Some(e) = self.error.take() { return Err(e) }
+        let _guard = self.vm.stack_guard();
+        self.build_inner(table)
+    }
+}
+
+/// A handle to a Squirrel class
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SqClass(SqObjectRef);
+
+impl SqClass {
+    /// Creates a new instance of the class that owns the value, without calling the class's
+    /// constructor
+    pub fn create_instance<T>(&self, vm: &mut SquirrelVM, value: T) -> Result<SqObjectRef, SquirrelError>
+    where T: SquirrelClass {
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        match unsafe { sq_createinstance(vm.handle, -1) } {
+            0 => unsafe { set_instance_up(vm, -1, value) }.map(|_| SqObjectRef::take_top(vm)),
+            _ => Err(SquirrelError::CouldNotCreateInstance)
+        }
+    }
+}
+
+impl Deref for SqClass {
+    type Target = SqObjectRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<SqClass> for SqObjectRef {
+    fn from(value: SqClass) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<SqObjectRef> for SqClass {
+    type Error = SquirrelError;
+
+    fn try_from(value: SqObjectRef) -> Result<Self, Self::Error> {
+        match value.is::<Self>() {
+            true => Ok(Self(value)),
+            false => Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
+    }
+}
+
+impl SquirrelObject for SqClass {
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.0.push_raw(vm) };
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        <SqObjectRef as SquirrelObject>::get(vm, index)?.try_into().map_err(|_| SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl SquirrelTypeId for SqClass {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_CLASS as _
+    }
+}
+
+impl CanSquirrel for SqClass {
+    type Into = SqClass;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "y".to_string()
+    }
+}
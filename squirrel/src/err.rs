@@ -1,13 +1,22 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::error::Error;
 use std::str::Utf8Error;
-use squirrel_sys::bindings::root::{SQInteger, HSQUIRRELVM};
+
+/// A function in the call stack when a runtime error was thrown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function: String,
+    pub source: String,
+    pub line: i64,
+    /// The name and value of each local variable. Values are formatted for display, e.g strings
+    /// are quoted and tables are shown as their type.
+    pub locals: Vec<(String, String)>
+}
 
 #[derive(Debug)]
 pub enum SquirrelError {
-    CouldNotCompileSource,
     GetWrongObjectType,
-    ErrorWhileCalling,
+    GetWrongObjectTypeForKey(String),
     CouldNotReadBytecode,
     CouldNotSuspendVM,
     CouldNotWakeupVM,
@@ -16,14 +25,60 @@ pub enum SquirrelError {
     CouldNotSetNativeClosureName,
     ObjectTypeDoesNotMatch,
     CouldNotFindFunction(String),
-    ForeignPointerNotSet
+    KeyNotFound(String),
+    CouldNotSetSlot(String),
+    IndexOutOfRange(usize),
+    /// Tried to pop an element from an empty array
+    ArrayEmpty,
+    CouldNotAppend,
+    ForeignPointerNotSet,
+    InstanceNotInitialized,
+    CouldNotCreateClass(String),
+    CouldNotCreateInstance,
+    /// `SqClassBuilder::params_check` was called before adding a constructor or method
+    ParamsCheckWithoutMember,
+    TypeTagMismatch { expected: String, found: String },
+    InvalidTypeMask(String),
+    ThreadNotSuspended,
+    /// Tried to start a thread that has already been started
+    ThreadNotIdle,
+    /// The VM that owns the object has been closed
+    VMClosed,
+    /// The value is already borrowed in a way that conflicts with the new borrow
+    AlreadyBorrowed,
+    #[cfg(feature = "serde")]
+    Serde(crate::serde::Error),
+    /// An error reported by the compiler. The snippet is the text of the line that the error is on.
+    Compile { desc: String, source: String, line: i64, column: i64, snippet: String },
+    /// An error thrown by a script. The stack starts at the function that threw the error.
+    Runtime { message: String, stack: Vec<StackFrame> }
 }
 
 impl Error for SquirrelError {}
 impl Display for SquirrelError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        <Self as Debug>::fmt(self, f)
+        match self {
+            Self::Runtime { message, stack } => {
+                write!(f, "{}", message)?;
+                for frame in stack {
+                    write!(f, "\n    at {} ({}:{})", frame.function, frame.source, frame.line)?;
+                }
+                Ok(())
+            },
+            Self::Compile { desc, source, line, column, snippet } => {
+                write!(f, "{} ({}:{}:{})", desc, source, line, column)?;
+                if !snippet.is_empty() {
+                    // the compiler reports the column after the token that caused the error
+                    let caret = (*column as usize).saturating_sub(1).min(snippet.chars().count());
+                    let indent: String = snippet.chars().take(caret)
+                        .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                    write!(f, "\n    {}\n    {}^", snippet, indent)?;
+                }
+                Ok(())
+            },
+            _ => <Self as Debug>::fmt(self, f)
+        }
     }
 }
 
-pub(crate) type ErrorCallback = unsafe extern "C" fn(HSQUIRRELVM) -> SQInteger;
\ No newline at end of file
+pub(crate) type ErrorCallback = fn(&SquirrelError);
\ No newline at end of file
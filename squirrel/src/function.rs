@@ -0,0 +1,182 @@
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::{SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::type_cnv::CanSquirrel;
+use crate::vm::SquirrelVM;
+
+/// The arguments of a call to a Squirrel function. This is implemented for tuples of
+/// [`CanSquirrel`] types, where each element is pushed as a parameter after `this`.
+pub trait SquirrelArgs {
+    /// The number of parameters, not including `this`
+    const COUNT: usize;
+
+    fn push_args(&self, vm: &mut SquirrelVM);
+}
+
+macro_rules! impl_squirrel_args {
+    ($($name:ident),*) => {
+        impl<$($name),*> SquirrelArgs for ($($name,)*) where $($name: CanSquirrel),* {
+            const COUNT: usize = $crate::sqvm_call_count_type_args!($($name),*);
+
+            #[allow(non_snake_case, unused_variables)]
+            fn push_args(&self, vm: &mut SquirrelVM) {
+                let ($($name,)*) = self;
+                $(vm.push($name);)*
+            }
+        }
+    };
+}
+
+impl_squirrel_args!();
+impl_squirrel_args!(A);
+impl_squirrel_args!(A, B);
+impl_squirrel_args!(A, B, C);
+impl_squirrel_args!(A, B, C, D);
+impl_squirrel_args!(A, B, C, D, E);
+impl_squirrel_args!(A, B, C, D, E, F);
+impl_squirrel_args!(A, B, C, D, E, F, G);
+impl_squirrel_args!(A, B, C, D, E, F, G, H);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K, L);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O);
+impl_squirrel_args!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+/// A handle to a Squirrel closure (or native closure) that takes `Args` and returns `Ret`. The
+/// closure is held for as long as the handle is alive, so it can be called repeatedly without
+/// looking it up again. Functions are called with the root table as `this`, unless an environment
+/// is set with [`SqFunction::with_env`].
+pub struct SqFunction<Args, Ret> {
+    closure: SqObjectRef,
+    env: Option<SqObjectRef>,
+    _type: PhantomData<fn(Args) -> Ret>
+}
+
+impl<Args, Ret> SqFunction<Args, Ret>
+where Args: SquirrelArgs, Ret: CanSquirrel {
+    /// Sets the object that's passed as `this` when the function is called, e.g an entity table
+    /// or a class instance
+    pub fn with_env<T: Into<SqObjectRef>>(mut self, env: T) -> Self {
+        self.env = Some(env.into());
+        self
+    }
+
+    /// The object that's passed as `this`, or None if the root table is used
+    pub fn env(&self) -> Option<&SqObjectRef> {
+        self.env.as_ref()
+    }
+
+    /// Calls the function with the given arguments. If the script throws an error, this returns
+    /// [`SquirrelError::Runtime`].
+    pub fn call(&self, vm: &mut SquirrelVM, args: Args) -> Result<Ret, SquirrelError> {
+        let _check = vm.stack_check();
+        self.call_inner(vm, self.env.as_ref(), args)
+    }
+
+    /// Calls the function with the given object as `this`, instead of the function's environment
+    pub fn call_with_env(&self, vm: &mut SquirrelVM, env: &SqObjectRef, args: Args) -> Result<Ret, SquirrelError> {
+        let _check = vm.stack_check();
+        self.call_inner(vm, Some(env), args)
+    }
+
+    fn call_inner(&self, vm: &mut SquirrelVM, env: Option<&SqObjectRef>, args: Args) -> Result<Ret, SquirrelError> {
+        let _guard = vm.stack_guard();
+        unsafe {
+            self.closure.push_raw(vm);
+            match env {
+                Some(env) => env.push_raw(vm),
+                None => sq_pushroottable(vm.handle)
+            }
+        }
+        args.push_args(vm);
+        vm.clear_runtime_error();
+        match unsafe { sq_call(vm.handle, (Args::COUNT + 1) as _, true.into(), true.into()) } {
+            0 => vm.get::<Ret>(1),
+            _ => Err(vm.take_runtime_error())
+        }
+    }
+}
+
+impl<Args, Ret> Clone for SqFunction<Args, Ret> {
+    fn clone(&self) -> Self {
+        Self { closure: self.closure.clone(), env: self.env.clone(), _type: PhantomData }
+    }
+}
+
+impl<Args, Ret> Debug for SqFunction<Args, Ret> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqFunction")
+            .field("closure", &self.closure)
+            .field("env", &self.env)
+            .finish()
+    }
+}
+
+impl<Args, Ret> Deref for SqFunction<Args, Ret> {
+    type Target = SqObjectRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.closure
+    }
+}
+
+impl<Args, Ret> From<SqFunction<Args, Ret>> for SqObjectRef {
+    fn from(value: SqFunction<Args, Ret>) -> Self {
+        value.closure
+    }
+}
+
+impl<Args, Ret> TryFrom<SqObjectRef> for SqFunction<Args, Ret> {
+    type Error = SquirrelError;
+
+    fn try_from(value: SqObjectRef) -> Result<Self, Self::Error> {
+        #[allow(non_upper_case_globals)]
+        match value.get_type() {
+            tagSQObjectType_OT_CLOSURE | tagSQObjectType_OT_NATIVECLOSURE => {
+                Ok(Self { closure: value, env: None, _type: PhantomData })
+            },
+            _ => Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
+    }
+}
+
+impl<Args, Ret> SquirrelObject for SqFunction<Args, Ret> {
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.closure.push_raw(vm) };
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        <SqObjectRef as SquirrelObject>::get(vm, index)?.try_into().map_err(|_| SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl<Args, Ret> SquirrelTypeId for SqFunction<Args, Ret> {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_CLOSURE as _
+    }
+}
+
+impl<Args, Ret> CanSquirrel for SqFunction<Args, Ret> {
+    type Into = SqFunction<Args, Ret>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "c".to_string()
+    }
+}
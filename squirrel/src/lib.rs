@@ -1,8 +1,18 @@
+pub mod args;
+pub mod array;
+pub mod class;
 pub mod err;
+pub mod function;
 pub mod obj_type;
 pub mod object;
 pub mod print_cb;
+#[cfg(feature = "serde")]
+pub mod serde;
+pub mod table;
+pub mod thread;
 pub mod type_cnv;
+pub mod userdata;
+pub mod value;
 pub mod vm;
 
 // Re-export squirrel-sys crate
@@ -1,24 +1,154 @@
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
+use std::sync::Mutex;
 use squirrel_sys::bindings::root::*;
 use crate::err::SquirrelError;
+use crate::array::{get_vec, push_slice, SqArray};
 use crate::object::SquirrelTypeId;
+use crate::table::{get_map, push_map};
+use crate::type_cnv::CanSquirrel;
 use crate::vm::SquirrelVM;
 
+#[derive(Default)]
+struct TypeTags {
+    tags: HashMap<TypeId, usize>,
+    names: HashMap<usize, &'static str>
+}
+
+static TYPE_TAGS: Mutex<Option<TypeTags>> = Mutex::new(None);
+
+thread_local! {
+    // Tags that this thread has already used, so that the global lock is only taken the first time
+    // a thread uses a type rather than on every call
+    static CACHED_TYPE_TAGS: RefCell<HashMap<TypeId, usize>> = RefCell::new(HashMap::new());
+}
+
+/// Returns a unique type tag for T. This is used to check that a class instance or userdata
+/// contains the expected Rust type before it's reinterpreted.
+pub fn type_tag<T: 'static>() -> SQUserPointer {
+    let id = TypeId::of::<T>();
+    if let Some(tag) = CACHED_TYPE_TAGS.with_borrow(|tags| tags.get(&id).copied()) {
+        return tag as SQUserPointer;
+    }
+    let mut tags = TYPE_TAGS.lock().unwrap();
+    let tags = tags.get_or_insert_with(TypeTags::default);
+    let tag = *tags.tags.entry(id).or_insert_with(|| {
+        let tag = Box::into_raw(Box::new(id)) as usize;
+        tags.names.insert(tag, std::any::type_name::<T>());
+        tag
+    });
+    CACHED_TYPE_TAGS.with_borrow_mut(|cached| cached.insert(id, tag));
+    tag as SQUserPointer
+}
+
+/// Gets the name of the Rust type that the type tag was created for
+pub(crate) fn type_tag_name(tag: SQUserPointer) -> String {
+    if tag.is_null() { return "untagged".to_string() }
+    TYPE_TAGS.lock().unwrap().as_ref()
+        .and_then(|t| t.names.get(&(tag as usize)))
+        .map_or("unknown".to_string(), |n| n.to_string())
+}
+
+pub(crate) fn type_name(ty: SQObjectType) -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match ty {
+        tagSQObjectType_OT_NULL => "null",
+        tagSQObjectType_OT_INTEGER => "integer",
+        tagSQObjectType_OT_FLOAT => "float",
+        tagSQObjectType_OT_BOOL => "bool",
+        tagSQObjectType_OT_STRING => "string",
+        tagSQObjectType_OT_TABLE => "table",
+        tagSQObjectType_OT_ARRAY => "array",
+        tagSQObjectType_OT_USERDATA => "userdata",
+        tagSQObjectType_OT_CLOSURE | tagSQObjectType_OT_NATIVECLOSURE => "function",
+        tagSQObjectType_OT_GENERATOR => "generator",
+        tagSQObjectType_OT_USERPOINTER => "userpointer",
+        tagSQObjectType_OT_THREAD => "thread",
+        tagSQObjectType_OT_CLASS => "class",
+        tagSQObjectType_OT_INSTANCE => "instance",
+        tagSQObjectType_OT_WEAKREF => "weakref",
+        _ => "unknown"
+    }
+}
+
+/// Describes the type of the value at the given stack index for errors. For tagged values, this is
+/// the name of the Rust type.
+pub(crate) fn stack_type_name(handle: HSQUIRRELVM, index: SQInteger) -> String {
+    let ty = unsafe { sq_gettype(handle, index) };
+    #[allow(non_upper_case_globals)]
+    match ty {
+        tagSQObjectType_OT_USERDATA | tagSQObjectType_OT_INSTANCE | tagSQObjectType_OT_CLASS => {
+            let mut tag: SQUserPointer = std::ptr::null_mut();
+            unsafe { sq_gettypetag(handle, index, &mut tag) };
+            format!("{} ({})", type_name(ty), type_tag_name(tag))
+        },
+        _ => type_name(ty).to_string()
+    }
+}
+
+pub(crate) fn check_type_tag<T: 'static>(handle: HSQUIRRELVM, index: SQInteger, tag: SQUserPointer) -> Result<(), SquirrelError> {
+    match tag == type_tag::<T>() {
+        true => Ok(()),
+        false => Err(SquirrelError::TypeTagMismatch {
+            expected: std::any::type_name::<T>().to_string(),
+            found: stack_type_name(handle, index)
+        })
+    }
+}
+
+/// A position on the stack. Absolute positions count up from the bottom of the current call's
+/// stack, so in a native function 1 is `this` and 2 is the first argument, as in Squirrel's own
+/// API. Relative positions count down from the top, where 1 is the value at the top of the stack.
+///
+/// A `usize` converts into a relative position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StackIndex {
+    Absolute(usize),
+    Relative(usize)
+}
+
+impl StackIndex {
+    /// The index that's passed to the Squirrel API, where negative indices are relative to the top
+    pub fn raw(self) -> SQInteger {
+        match self {
+            Self::Absolute(i) => i as SQInteger,
+            Self::Relative(i) => -(i as SQInteger)
+        }
+    }
+
+    /// Converts the index into an absolute position, which stays the same when values are pushed
+    pub fn to_absolute(self, vm: &SquirrelVM) -> Self {
+        match self {
+            Self::Relative(i) => Self::Absolute((unsafe { sq_gettop(vm.handle) } - i as SQInteger + 1) as usize),
+            v => v
+        }
+    }
+}
+
+impl From<usize> for StackIndex {
+    fn from(value: usize) -> Self {
+        Self::Relative(value)
+    }
+}
+
 pub trait SquirrelObject : SquirrelTypeId {
     fn push(&self, vm: &mut SquirrelVM);
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError>;
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError>;
 }
 
 impl SquirrelObject for SQInteger {
     fn push(&self, vm: &mut SquirrelVM) {
         unsafe { sq_pushinteger(vm.handle, *self) };
     }
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
         let mut value: Self = 0;
-        let res = unsafe { sq_getinteger(vm.handle, -(index as i64), &mut value) };
+        let res = unsafe { sq_getinteger(vm.handle, index.raw(), &mut value) };
         if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
         Ok(value)
     }
@@ -34,9 +164,9 @@ impl SquirrelObject for SQFloat {
     fn push(&self, vm: &mut SquirrelVM) {
         unsafe { sq_pushfloat(vm.handle, *self) };
     }
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
         let mut value: Self = 0.;
-        let res = unsafe { sq_getfloat(vm.handle, -(index as i64), &mut value) };
+        let res = unsafe { sq_getfloat(vm.handle, index.raw(), &mut value) };
         if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
         Ok(value)
     }
@@ -52,9 +182,9 @@ impl SquirrelObject for SQBool {
     fn push(&self, vm: &mut SquirrelVM) {
         unsafe { sq_pushbool(vm.handle, *self) };
     }
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
         let mut value: Self = false.into();
-        let res = unsafe { sq_getbool(vm.handle, -(index as i64), &mut value) };
+        let res = unsafe { sq_getbool(vm.handle, index.raw(), &mut value) };
         if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
         Ok(value)
     }
@@ -70,7 +200,7 @@ impl SquirrelObject for () {
     fn push(&self, vm: &mut SquirrelVM) {
         unsafe { sq_pushnull(vm.handle) };
     }
-    fn get(_: &SquirrelVM, _: usize) -> Result<Self, SquirrelError> {
+    fn get(_: &SquirrelVM, _: StackIndex) -> Result<Self, SquirrelError> {
         Ok(())
     }
 }
@@ -86,9 +216,9 @@ impl SquirrelObject for String {
         unsafe { sq_pushstring(vm.handle, self.as_ptr() as _, self.len() as i64) };
     }
 
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
         let mut out_str = std::ptr::null();
-        let res = unsafe { sq_getstring(vm.handle, -(index as i64), &mut out_str) };
+        let res = unsafe { sq_getstring(vm.handle, index.raw(), &mut out_str) };
         if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
         let out_str = unsafe { std::ffi::CStr::from_ptr(out_str).to_str()
             .map_err(|e| SquirrelError::Utf8Error(e))? };
@@ -102,6 +232,29 @@ impl SquirrelTypeId for String {
     }
 }
 
+impl<T> SquirrelObject for Option<T> where T: SquirrelObject {
+    fn push(&self, vm: &mut SquirrelVM) {
+        match self {
+            Some(v) => v.push(vm),
+            None => unsafe { sq_pushnull(vm.handle) }
+        }
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        #[allow(non_upper_case_globals)]
+        match unsafe { sq_gettype(vm.handle, index.raw()) } {
+            tagSQObjectType_OT_NULL => Ok(None),
+            _ => T::get(vm, index).map(|v| Some(v))
+        }
+    }
+}
+
+impl<T> SquirrelTypeId for Option<T> where T: SquirrelTypeId {
+    fn type_id() -> u32 {
+        T::type_id()
+    }
+}
+
 pub struct UserPointer<T>(NonNull<T>);
 
 impl<T> UserPointer<T> {
@@ -141,21 +294,175 @@ impl<T> From<SQUserPointer> for UserPointer<T> {
     }
 }
 
-impl<T> SquirrelObject for UserPointer<T> {
+// Reads the pointer from a userdata pushed by UserPointer. Class instances and UserData<T> own
+// their value, so they're rejected even though they're tagged with T's type tag.
+pub(crate) fn get_tagged_pointer<T: 'static>(handle: HSQUIRRELVM, index: SQInteger) -> Result<NonNull<T>, SquirrelError> {
+    let mut p: SQUserPointer = std::ptr::null_mut();
+    #[allow(non_upper_case_globals)]
+    let ptr = match unsafe { sq_gettype(handle, index) } {
+        tagSQObjectType_OT_USERDATA => {
+            let mut tag: SQUserPointer = std::ptr::null_mut();
+            unsafe { sq_getuserdata(handle, index, &mut p, &mut tag) };
+            if tag != type_tag::<UserPointer<T>>() {
+                return Err(SquirrelError::TypeTagMismatch {
+                    expected: std::any::type_name::<T>().to_string(),
+                    found: stack_type_name(handle, index)
+                });
+            }
+            unsafe { *(p as *mut *mut T) }
+        },
+        tagSQObjectType_OT_INSTANCE | tagSQObjectType_OT_USERPOINTER => return Err(SquirrelError::TypeTagMismatch {
+            expected: std::any::type_name::<T>().to_string(),
+            found: stack_type_name(handle, index)
+        }),
+        _ => return Err(SquirrelError::GetWrongObjectType)
+    };
+    NonNull::new(ptr).ok_or(SquirrelError::InstanceNotInitialized)
+}
+
+// Pointers are pushed as a userdata containing the pointer. The userdata is tagged with
+// UserPointer<T>'s type tag rather than T's, so it can't be confused with a UserData<T> or class
+// instance. Unlike UserData, this has no release hook since the value is borrowed.
+impl<T: 'static> SquirrelObject for UserPointer<T> {
     fn push(&self, vm: &mut SquirrelVM) {
-        unsafe { sq_pushuserpointer(vm.handle, self.into()) };
+        unsafe {
+            let p = sq_newuserdata(vm.handle, size_of::<*mut T>() as _) as *mut *mut T;
+            *p = self.0.as_ptr();
+            sq_settypetag(vm.handle, -1, type_tag::<UserPointer<T>>());
+        }
     }
 
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
-        let mut ptr: SQUserPointer = std::ptr::null_mut();
-        let res = unsafe { sq_getuserpointer(vm.handle, -(index as i64), &mut ptr) };
-        if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
-        Ok(ptr.into())
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        get_tagged_pointer::<T>(vm.handle, index.raw()).map(|p| UserPointer(p))
+    }
+}
+
+impl<T: 'static> SquirrelTypeId for UserPointer<T> {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_USERDATA as _
+    }
+}
+
+impl<T> SquirrelObject for Vec<T> where T: CanSquirrel {
+    fn push(&self, vm: &mut SquirrelVM) {
+        push_slice(vm, self);
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        get_vec(vm, index)
+    }
+}
+
+impl<T> SquirrelTypeId for Vec<T> {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_ARRAY as _
+    }
+}
+
+// Tuples are pushed as arrays, and read from arrays with at least as many elements as the tuple
+macro_rules! tuple_sq_object {
+    ($($name:ident $idx:tt),*) => {
+        impl<$($name),*> SquirrelObject for ($($name,)*) where $($name: CanSquirrel),* {
+            fn push(&self, vm: &mut SquirrelVM) {
+                unsafe { sq_newarray(vm.handle, 0) };
+                $(
+                    vm.push(&self.$idx);
+                    unsafe { sq_arrayappend(vm.handle, -2) };
+                )*
+            }
+
+            fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+                let array = <SqArray as SquirrelObject>::get(vm, index)?;
+                Ok(($(array.get::<$name>(vm, $idx)?,)*))
+            }
+        }
+
+        impl<$($name),*> SquirrelTypeId for ($($name,)*) {
+            fn type_id() -> u32 {
+                tagSQObjectType_OT_ARRAY as _
+            }
+        }
+    };
+}
+
+tuple_sq_object!(A 0);
+tuple_sq_object!(A 0, B 1);
+tuple_sq_object!(A 0, B 1, C 2);
+tuple_sq_object!(A 0, B 1, C 2, D 3);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11, M 12);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11, M 12, N 13);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11, M 12, N 13, O 14);
+tuple_sq_object!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11, M 12, N 13, O 14, P 15);
+
+impl<T, const N: usize> SquirrelObject for [T; N] where T: CanSquirrel {
+    fn push(&self, vm: &mut SquirrelVM) {
+        push_slice(vm, self);
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        get_vec(vm, index)?.try_into().map_err(|_| SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl<T, const N: usize> SquirrelTypeId for [T; N] {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_ARRAY as _
+    }
+}
+
+impl<T> SquirrelObject for &[T] where T: CanSquirrel {
+    fn push(&self, vm: &mut SquirrelVM) {
+        push_slice(vm, self);
+    }
+
+    // A borrowed slice can't point into a Squirrel array, use Vec<T> to read arrays instead
+    fn get(_: &SquirrelVM, _: StackIndex) -> Result<Self, SquirrelError> {
+        Err(SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl<T> SquirrelTypeId for &[T] {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_ARRAY as _
+    }
+}
+
+impl<K, V> SquirrelObject for HashMap<K, V> where K: CanSquirrel + Eq + Hash, V: CanSquirrel {
+    fn push(&self, vm: &mut SquirrelVM) {
+        push_map(vm, self.iter());
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        get_map(vm, index)
+    }
+}
+
+impl<K, V> SquirrelTypeId for HashMap<K, V> {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_TABLE as _
+    }
+}
+
+impl<K, V> SquirrelObject for BTreeMap<K, V> where K: CanSquirrel + Ord, V: CanSquirrel {
+    fn push(&self, vm: &mut SquirrelVM) {
+        push_map(vm, self.iter());
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        get_map(vm, index)
     }
 }
 
-impl<T> SquirrelTypeId for UserPointer<T> {
+impl<K, V> SquirrelTypeId for BTreeMap<K, V> {
     fn type_id() -> u32 {
-        tagSQObjectType_OT_USERPOINTER as _
+        tagSQObjectType_OT_TABLE as _
     }
 }
\ No newline at end of file
@@ -1,130 +1,359 @@
-use std::marker::PhantomData;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::mem::MaybeUninit;
+use std::sync::{atomic, Arc};
 use squirrel_sys::bindings::root::*;
 use crate::err::SquirrelError;
-use crate::obj_type::{SquirrelObject, UserPointer};
-use crate::vm::SquirrelVM;
+use crate::obj_type::{get_tagged_pointer, SquirrelObject, StackIndex, UserPointer};
+use crate::table::SqTable;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::{SquirrelVM, SquirrelVMOwner};
 
 pub trait SquirrelTypeId where Self: Sized {
     fn type_id() -> u32;
 }
 
-pub struct UserObject<T> {
-    handle: Box<HSQOBJECT>,
+/// Type ID for values that can contain any type of Squirrel object
+pub const ANY_TYPE_ID: u32 = _RT_MASK;
+
+/// An owned handle to any object inside of a Squirrel VM. This holds a strong reference to the
+/// object (sq_addref) for as long as the handle is alive, so tables, closures, classes and
+/// instances can be held onto across calls from Rust.
+///
+/// Handles can be sent to other threads along with their VM. Cloning a handle doesn't touch the
+/// VM, and once the last clone is dropped the reference is released by the next call into the
+/// VM, so handles can be dropped on any thread.
+#[derive(Clone)]
+pub struct SqObjectRef {
+    inner: Arc<ObjectHandle>
+}
+
+// The reference held by every clone of an SqObjectRef
+struct ObjectHandle {
+    handle: HSQOBJECT,
     // for drop
-    _owner: HSQUIRRELVM,
-    _type: PhantomData<T>
+    owner: Arc<SquirrelVMOwner>
 }
 
-impl<T> UserObject<T> where T: SquirrelTypeId {
-    fn push(&self, vm: &mut SquirrelVM) {
-        todo!()
+// The handle is only used by the thread that owns the VM. Dropping it from any other thread only
+// queues the object to be released.
+unsafe impl Send for ObjectHandle {}
+unsafe impl Sync for ObjectHandle {}
+
+impl Drop for ObjectHandle {
+    fn drop(&mut self) {
+        // the VM releases every object that it owns when it's closed
+        let mut released = self.owner.released.lock().unwrap();
+        if self.owner.alive.load(atomic::Ordering::Acquire) {
+            released.push(self.handle);
+            self.owner.has_released.store(true, atomic::Ordering::Release);
+        }
+    }
+}
+
+impl SqObjectRef {
+    pub(crate) fn from_raw(vm: &SquirrelVM, mut handle: HSQOBJECT) -> Self {
+        unsafe { sq_addref(vm.handle, &mut handle) };
+        Self { inner: Arc::new(ObjectHandle { handle, owner: vm.owner.clone() }) }
+    }
+
+    /// Returns the raw Squirrel object.
+    ///
+    /// # Safety
+    ///
+    /// The returned object is borrowed from this handle. It must not be released, and must not be
+    /// used after the handle (and every clone of it) has been dropped, or with a VM other than the
+    /// one that created the handle.
+    pub unsafe fn raw(&self) -> HSQOBJECT {
+        self.inner.handle
+    }
+
+    pub fn get_type(&self) -> SQObjectType {
+        self.inner.handle._type
+    }
+
+    /// Checks if the referenced object has the same type as T
+    pub fn is<T: SquirrelTypeId>(&self) -> bool {
+        T::type_id() == ANY_TYPE_ID || self.inner.handle._type as u32 == T::type_id()
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.inner.handle._type == tagSQObjectType_OT_NULL
     }
 
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self, SquirrelError> {
+    // Takes the object at the top of the stack and pops it
+    pub(crate) fn take_top(vm: &SquirrelVM) -> Self {
         let mut out_obj = MaybeUninit::uninit();
-        let res = unsafe { sq_getstackobj(vm.handle, -(index as i64), out_obj.as_mut_ptr()) };
-        if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
-        let mut handle = Box::new(unsafe { out_obj.assume_init() });
-        if handle._type != T::type_id() as _ { return Err(SquirrelError::ObjectTypeDoesNotMatch) }
-        unsafe { sq_addref(vm.handle, handle.as_mut()) };
-        Ok(Self { handle, _owner: vm.handle, _type: PhantomData::<T> })
+        unsafe { sq_getstackobj(vm.handle, -1, out_obj.as_mut_ptr()) };
+        let obj = Self::from_raw(vm, unsafe { out_obj.assume_init() });
+        unsafe { sq_poptop(vm.handle) };
+        obj
     }
-}
 
-impl<T> Drop for UserObject<T> {
-    fn drop(&mut self) {
-        unsafe { sq_release(self._owner, self.handle.as_mut()) };
+    /// Checks that the VM that created the object hasn't been closed
+    pub(crate) fn is_alive(&self) -> bool {
+        self.inner.owner.alive.load(atomic::Ordering::Acquire)
+    }
+
+    /// Checks that the object was created by the given VM, and that the VM hasn't been closed
+    pub(crate) fn is_owned_by(&self, vm: &SquirrelVM) -> bool {
+        Arc::ptr_eq(&self.inner.owner, &vm.owner) && self.inner.owner.alive.load(atomic::Ordering::Acquire)
+    }
+
+    // Panics if the object belongs to another VM, since its handle isn't valid in this one
+    pub(crate) unsafe fn push_raw(&self, vm: &SquirrelVM) {
+        assert!(self.is_owned_by(vm), "tried to push an object from another Squirrel VM");
+        unsafe { sq_pushobject(vm.handle, self.inner.handle) };
+    }
+
+    /// Converts the object into a string like `tostring` in a script, calling the `_tostring`
+    /// metamethod of instances and tables with a delegate
+    pub fn to_display_string(&self, vm: &mut SquirrelVM) -> Result<String, SquirrelError> {
+        let _guard = vm.stack_guard();
+        unsafe { self.push_raw(vm) };
+        vm.clear_runtime_error();
+        match unsafe { sq_tostring(vm.handle, -1) } {
+            0 => vm.get::<String>(1),
+            _ => Err(vm.take_runtime_error())
+        }
+    }
+
+    /// Gets the object's type name like `typeof` in a script, which can be overridden with the
+    /// `_typeof` metamethod
+    pub fn type_of(&self, vm: &mut SquirrelVM) -> Result<String, SquirrelError> {
+        let _guard = vm.stack_guard();
+        unsafe { self.push_raw(vm) };
+        vm.clear_runtime_error();
+        match unsafe { sq_typeof(vm.handle, -1) } {
+            0 => vm.get::<String>(1),
+            _ => Err(vm.take_runtime_error())
+        }
+    }
+
+    /// Compares the object with another value like `<=>` in a script, calling the `_cmp`
+    /// metamethod if it's defined. Values that can't be compared return an error.
+    pub fn cmp<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<Ordering, SquirrelError> {
+        let _guard = vm.stack_guard();
+        vm.push(other);
+        unsafe { self.push_raw(vm) };
+        vm.clear_runtime_error();
+        // compares the top of the stack with the value below it. A failed comparison can only be
+        // detected through the last error.
+        let res = unsafe { sq_cmp(vm.handle) };
+        let failed = unsafe {
+            sq_getlasterror(vm.handle);
+            sq_gettype(vm.handle, -1) != tagSQObjectType_OT_NULL
+        };
+        match failed {
+            true => Err(vm.take_runtime_error()),
+            false => Ok(res.cmp(&0))
+        }
+    }
+
+    /// Applies an arithmetic operator to the object and another value, calling metamethods such
+    /// as `_add` for instances and tables with a delegate.
+    ///
+    /// The Squirrel API doesn't expose arithmetic, so this calls a script which applies the
+    /// operator. Each operator's script is compiled once per VM and kept in the registry table.
+    pub fn arith<T: CanSquirrel>(&self, vm: &mut SquirrelVM, op: ArithOp, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        let _guard = vm.stack_guard();
+        let closure = op.closure(vm)?;
+        unsafe {
+            closure.push_raw(vm);
+            sq_pushroottable(vm.handle);
+            self.push_raw(vm);
+        }
+        vm.push(other);
+        vm.clear_runtime_error();
+        match unsafe { sq_call(vm.handle, 3, true.into(), true.into()) } {
+            0 => <SqObjectRef as SquirrelObject>::get(vm, StackIndex::Relative(1)),
+            _ => Err(vm.take_runtime_error())
+        }
+    }
+
+    pub fn add<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        self.arith(vm, ArithOp::Add, other)
+    }
+
+    pub fn sub<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        self.arith(vm, ArithOp::Sub, other)
+    }
+
+    pub fn mul<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        self.arith(vm, ArithOp::Mul, other)
+    }
+
+    pub fn div<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        self.arith(vm, ArithOp::Div, other)
+    }
+
+    pub fn rem<T: CanSquirrel>(&self, vm: &mut SquirrelVM, other: &T) -> Result<SqObjectRef, SquirrelError> {
+        self.arith(vm, ArithOp::Mod, other)
     }
 }
 
-pub struct Table;
+/// An arithmetic operator for [`SqObjectRef::arith`], which calls the matching metamethod (e.g
+/// `_add` for `Add`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod
+}
 
-impl SquirrelTypeId for Table {
-    fn type_id() -> u32 {
-        tagSQObjectType_OT_TABLE as _
+impl ArithOp {
+    fn operator(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Mod => "%"
+        }
+    }
+
+    // Gets the closure which applies the operator, compiling it the first time it's used. The
+    // closure is kept in the VM's registry table rather than alongside the VM's owner, which would
+    // keep the owner alive through its own objects.
+    fn closure(self, vm: &mut SquirrelVM) -> Result<SqObjectRef, SquirrelError> {
+        let registry = SqTable::registry(vm);
+        let key = format!("sqcrab.arith{}", self.operator());
+        match registry.get::<String, SqObjectRef>(vm, &key) {
+            Err(SquirrelError::KeyNotFound(_)) => {
+                vm.try_compile(&format!("return vargv[0] {} vargv[1];", self.operator()), "arith")?;
+                let closure = SqObjectRef::take_top(vm);
+                registry.new_slot(vm, &key, &closure)?;
+                Ok(closure)
+            },
+            res => res
+        }
     }
 }
 
-pub struct Array;
+impl SquirrelObject for SqObjectRef {
+    /// # Panics
+    ///
+    /// Panics if the object was created by a different VM
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.push_raw(vm) };
+    }
 
-impl SquirrelTypeId for Array {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        let mut out_obj = MaybeUninit::uninit();
+        let res = unsafe { sq_getstackobj(vm.handle, index.raw(), out_obj.as_mut_ptr()) };
+        if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
+        Ok(Self::from_raw(vm, unsafe { out_obj.assume_init() }))
+    }
+}
+
+impl SquirrelTypeId for SqObjectRef {
     fn type_id() -> u32 {
-        tagSQObjectType_OT_ARRAY as _
+        ANY_TYPE_ID
     }
 }
 
-/*
-impl<'a> From<&'a UserObject<SQBool>> for SQBool {
-    fn from(value: &'a UserObject<SQBool>) -> Self {
-        unsafe { sq_objtobool(value.handle.as_ref()) }
+impl CanSquirrel for SqObjectRef {
+    type Into = SqObjectRef;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
     }
 }
 
-impl<'a> From<&'a UserObject<SQFloat>> for SQFloat {
-    fn from(value: &'a UserObject<SQFloat>) -> Self {
-        unsafe { sq_objtofloat(value.handle.as_ref()) }
+// Equality is by identity: two handles are equal if they point to the same object (or contain the
+// same value for non reference counted types)
+impl PartialEq for SqObjectRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner.handle._type == other.inner.handle._type
+            && unsafe { self.inner.handle._unVal.raw == other.inner.handle._unVal.raw }
     }
 }
 
-impl<'a> From<&'a UserObject<SQInteger>> for SQInteger {
-    fn from(value: &'a UserObject<SQInteger>) -> Self {
-        unsafe { sq_objtointeger(value.handle.as_ref()) }
+impl Eq for SqObjectRef {}
+
+impl Hash for SqObjectRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.handle._type.hash(state);
+        unsafe { self.inner.handle._unVal.raw }.hash(state);
     }
 }
 
-impl<'a> From<&'a UserObject<String>> for String {
-    fn from(value: &'a UserObject<String>) -> Self {
-        let value = unsafe { sq_objtostring(value.handle.as_ref()) };
-        unsafe { std::ffi::CStr::from_ptr(value).to_str().unwrap().to_owned() }
+impl Debug for SqObjectRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SqObjectRef {{ type: 0x{:x}, value: 0x{:x} }}",
+               self.inner.handle._type, unsafe { self.inner.handle._unVal.raw })
     }
 }
 
-impl<'a, T> From<&'a UserObject<SQUserPointer>> for UserPointer<T> {
-    fn from(value: &'a UserObject<SQUserPointer>) -> Self {
-        UserPointer::<T>::new(unsafe { &*(sq_objtouserpointer(value.handle.as_ref()) as *const T) })
+#[deprecated(note = "use SqTable, which can also be used to access the table's slots")]
+pub type Table = crate::table::SqTable;
+
+#[deprecated(note = "use SqArray, which can also be used to access the array's elements")]
+pub type Array = crate::array::SqArray;
+
+impl SqObjectRef {
+    fn check_type(&self, type_id: tagSQObjectType) -> Result<(), SquirrelError> {
+        match self.inner.handle._type == type_id {
+            true => Ok(()),
+            false => Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
     }
 }
-*/
 
-impl<'a> TryFrom<&'a UserObject<SQBool>> for SQBool {
+impl<'a> TryFrom<&'a SqObjectRef> for SQBool {
     type Error = SquirrelError;
 
-    fn try_from(value: &'a UserObject<SQBool>) -> Result<Self, Self::Error> {
-        Ok(unsafe { sq_objtobool(value.handle.as_ref()) })
+    fn try_from(value: &'a SqObjectRef) -> Result<Self, Self::Error> {
+        value.check_type(tagSQObjectType_OT_BOOL)?;
+        Ok(unsafe { sq_objtobool(&value.inner.handle) })
     }
 }
 
-impl<'a> TryFrom<&'a UserObject<SQFloat>> for SQFloat {
+impl<'a> TryFrom<&'a SqObjectRef> for SQFloat {
     type Error = SquirrelError;
 
-    fn try_from(value: &'a UserObject<SQFloat>) -> Result<Self, Self::Error> {
-        Ok(unsafe { sq_objtofloat(value.handle.as_ref()) })
+    fn try_from(value: &'a SqObjectRef) -> Result<Self, Self::Error> {
+        value.check_type(tagSQObjectType_OT_FLOAT)?;
+        Ok(unsafe { sq_objtofloat(&value.inner.handle) })
     }
 }
 
-impl<'a> TryFrom<&'a UserObject<SQInteger>> for SQInteger {
+impl<'a> TryFrom<&'a SqObjectRef> for SQInteger {
     type Error = SquirrelError;
 
-    fn try_from(value: &'a UserObject<SQInteger>) -> Result<Self, Self::Error> {
-        Ok(unsafe { sq_objtointeger(value.handle.as_ref()) })
+    fn try_from(value: &'a SqObjectRef) -> Result<Self, Self::Error> {
+        value.check_type(tagSQObjectType_OT_INTEGER)?;
+        Ok(unsafe { sq_objtointeger(&value.inner.handle) })
     }
 }
 
-impl<'a> TryFrom<&'a UserObject<String>> for String {
+impl<'a> TryFrom<&'a SqObjectRef> for String {
     type Error = SquirrelError;
 
-    fn try_from(value: &'a UserObject<String>) -> Result<Self, Self::Error> {
-        let value = unsafe { sq_objtostring(value.handle.as_ref()) };
+    fn try_from(value: &'a SqObjectRef) -> Result<Self, Self::Error> {
+        value.check_type(tagSQObjectType_OT_STRING)?;
+        let value = unsafe { sq_objtostring(&value.inner.handle) };
         Ok(unsafe { std::ffi::CStr::from_ptr(value).to_str()
             .map_err(|e| SquirrelError::Utf8Error(e))?.to_owned() })
     }
 }
 
-impl<'a, T> TryFrom<&'a UserObject<SQUserPointer>> for UserPointer<T> {
-    type Error = SquirrelError;
-
-    fn try_from(value: &'a UserObject<SQUserPointer>) -> Result<Self, Self::Error> {
-        Ok(UserPointer::<T>::new(unsafe { &*(sq_objtouserpointer(value.handle.as_ref()) as *const T) }))
+impl SqObjectRef {
+    /// Reads the pointer from a userdata pushed by [`UserPointer`]. This goes through the VM's
+    /// stack, so it needs the VM rather than being a `TryFrom` conversion like other values.
+    pub fn to_user_pointer<T: 'static>(&self, vm: &SquirrelVM) -> Result<UserPointer<T>, SquirrelError> {
+        if !self.is_owned_by(vm) { return Err(SquirrelError::VMClosed) }
+        let _guard = vm.stack_guard();
+        unsafe { self.push_raw(vm) };
+        get_tagged_pointer::<T>(vm.handle, -1).map(|p| UserPointer::<T>::new(unsafe { p.as_ref() }))
     }
-}
\ No newline at end of file
+}
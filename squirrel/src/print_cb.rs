@@ -1,7 +1,11 @@
 use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::panic::AssertUnwindSafe;
 use std::sync::{Mutex, MutexGuard};
 use squirrel_sys::bindings::root::*;
-use crate::vm::{SquirrelVM, ThreadSafeSquirrelVMPointer};
+use crate::err::{ErrorCallback, SquirrelError, StackFrame};
+use crate::obj_type::stack_type_name;
+use crate::vm::{SquirrelVM, SquirrelVMOwner, ThreadSafeSquirrelVMPointer};
 // print/error
 
 type CallbackTypeBase<T> = Option<HashMap<ThreadSafeSquirrelVMPointer, T>>;
@@ -92,15 +96,127 @@ pub(crate) fn get_compiler_error_callback(vm: HSQUIRRELVM) -> Option<CompilerErr
         .get(&ThreadSafeSquirrelVMPointer(vm)).map(|v| *v)
 }
 
+// The last error reported by the compiler, which is taken once compilation returns
+static COMPILE_ERRORS: Mutex<CallbackTypeBase<(String, String, i64, i64)>> = Mutex::new(None);
+
+pub(crate) fn take_compile_error(vm: HSQUIRRELVM) -> Option<(String, String, i64, i64)> {
+    get_print_format_callbacks(&COMPILE_ERRORS).as_mut().unwrap()
+        .remove(&ThreadSafeSquirrelVMPointer(vm))
+}
+
 #[unsafe(no_mangle)]
 pub(crate) unsafe extern "C" fn sq_compile_error_callback(vm: HSQUIRRELVM, desc: *const SQChar, source: *const SQChar, line: SQInteger, column: SQInteger) {
-    let desc = unsafe { std::ffi::CStr::from_ptr(desc).to_str().unwrap() };
-    let source = unsafe { std::ffi::CStr::from_ptr(source).to_str().unwrap() };
+    let desc = unsafe { get_str(desc, "") };
+    let source = unsafe { get_str(source, "unknown") };
     if let Some(cb) = get_compiler_error_callback(vm) {
-        cb(desc, source, line, column);
+        cb(&desc, &source, line, column);
+    }
+    get_print_format_callbacks(&COMPILE_ERRORS).as_mut().unwrap()
+        .insert(ThreadSafeSquirrelVMPointer(vm), (desc, source, line, column));
+}
+
+// runtime error
+
+static RUNTIME_ERROR_CALLBACKS: Mutex<CallbackTypeBase<ErrorCallback>> = Mutex::new(None);
+
+pub(crate) fn register_runtime_error_callback(vm: HSQUIRRELVM, cb: ErrorCallback) {
+    get_print_format_callbacks(&RUNTIME_ERROR_CALLBACKS).as_mut().unwrap()
+        .insert(ThreadSafeSquirrelVMPointer(vm), cb);
+}
+
+pub(crate) fn remove_runtime_error_callback(vm: HSQUIRRELVM) {
+    get_print_format_callbacks(&RUNTIME_ERROR_CALLBACKS).as_mut().unwrap()
+        .remove(&ThreadSafeSquirrelVMPointer(vm));
+}
+
+pub(crate) fn get_runtime_error_callback(vm: HSQUIRRELVM) -> Option<ErrorCallback> {
+    get_print_format_callbacks(&RUNTIME_ERROR_CALLBACKS).as_ref().unwrap()
+        .get(&ThreadSafeSquirrelVMPointer(vm)).copied()
+}
+
+unsafe fn get_str(ptr: *const SQChar, default: &str) -> String {
+    match ptr.is_null() {
+        true => default.to_string(),
+        false => unsafe { std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned() }
+    }
+}
+
+// Formats the value at the given stack index without calling any metamethods
+unsafe fn describe_value(vm: HSQUIRRELVM, index: SQInteger) -> String {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sq_gettype(vm, index) } {
+        tagSQObjectType_OT_STRING => {
+            let mut out = std::ptr::null();
+            unsafe { sq_getstring(vm, index, &mut out) };
+            format!("\"{}\"", unsafe { get_str(out, "") })
+        },
+        tagSQObjectType_OT_NULL | tagSQObjectType_OT_INTEGER | tagSQObjectType_OT_FLOAT | tagSQObjectType_OT_BOOL => unsafe {
+            sq_tostring(vm, index);
+            let mut out = std::ptr::null();
+            sq_getstring(vm, -1, &mut out);
+            let value = get_str(out, "");
+            sq_poptop(vm);
+            value
+        },
+        _ => stack_type_name(vm, index)
     }
 }
 
+// Thrown strings are used as they are, other values are described
+pub(crate) unsafe fn error_message(vm: HSQUIRRELVM, index: SQInteger) -> String {
+    match unsafe { sq_gettype(vm, index) } == tagSQObjectType_OT_STRING {
+        true => unsafe {
+            let mut out = std::ptr::null();
+            sq_getstring(vm, index, &mut out);
+            get_str(out, "")
+        },
+        false => unsafe { describe_value(vm, index) }
+    }
+}
+
+// Reads the error message and each frame of the call stack. Level 0 is the error handler.
+unsafe fn build_runtime_error(vm: HSQUIRRELVM, error_index: SQInteger) -> SquirrelError {
+    let message = unsafe { error_message(vm, error_index) };
+    let mut stack = vec![];
+    let mut level = 1;
+    let mut info: MaybeUninit<SQStackInfos> = MaybeUninit::uninit();
+    while unsafe { sq_stackinfos(vm, level, info.as_mut_ptr()) } == 0 {
+        let info = unsafe { info.assume_init_ref() };
+        let mut locals = vec![];
+        loop {
+            let name = unsafe { sq_getlocal(vm, level as _, locals.len() as _) };
+            if name.is_null() { break }
+            locals.push((unsafe { get_str(name, "") }, unsafe { describe_value(vm, -1) }));
+            unsafe { sq_poptop(vm) };
+        }
+        stack.push(StackFrame {
+            function: unsafe { get_str(info.funcname, "unknown") },
+            source: unsafe { get_str(info.source, "unknown") },
+            line: info.line,
+            locals
+        });
+        level += 1;
+    }
+    SquirrelError::Runtime { message, stack }
+}
+
+// Set as the VM's error handler, which is called with the root table and the error. The VM's owner
+// is bound as a free variable, and stores the error until the failed call returns.
+pub(crate) unsafe extern "C" fn sq_runtime_error_handler(vm: HSQUIRRELVM) -> SQInteger {
+    let mut owner: SQUserPointer = std::ptr::null_mut();
+    if unsafe { sq_getuserpointer(vm, -1, &mut owner) } != 0 { return 0; }
+    let owner = unsafe { &*(owner as *const SquirrelVMOwner) };
+    // unwinding into the VM would abort, so a panicking callback only loses the error
+    let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let error = unsafe { build_runtime_error(vm, 2) };
+        if let Some(cb) = get_runtime_error_callback(owner.handle) {
+            cb(&error);
+        }
+        *owner.last_error.lock().unwrap() = Some(error);
+    }));
+    0
+}
+
 // debug hook
 
 #[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq)]
@@ -139,7 +255,7 @@ pub(crate) fn remove_debug_hook_callback(vm: HSQUIRRELVM) {
 
 pub(crate) fn get_debug_hook_callback(vm: HSQUIRRELVM) -> Option<DebugHookCallback> {
     get_print_format_callbacks(&DEBUG_HOOK_CALLBACKS).as_ref().unwrap()
-        .get(&ThreadSafeSquirrelVMPointer(vm)).map(|v| *v)
+        .get(&ThreadSafeSquirrelVMPointer(vm)).copied()
 }
 
 #[unsafe(no_mangle)]
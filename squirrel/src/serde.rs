@@ -0,0 +1,161 @@
+//! Serde support for moving structured data in and out of a Squirrel VM. Enabled with the `serde`
+//! feature.
+//!
+//! Values are mapped to Squirrel types as follows:
+//! - structs and maps are converted to tables
+//! - sequences, tuples and tuple structs are converted to arrays
+//! - unit variants are converted to a string containing the variant name
+//! - other enum variants are converted to a table with a single slot, `{ Variant = value }`
+//! - `None` and `()` are converted to null
+
+use std::fmt::{Display, Formatter};
+use ::serde::de::DeserializeOwned;
+use ::serde::Serialize;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::StackIndex;
+use crate::object::{SquirrelTypeId, ANY_TYPE_ID};
+use crate::vm::SquirrelVM;
+
+mod de;
+mod ser;
+
+pub use de::Deserializer;
+pub use ser::Serializer;
+
+/// A single step in the path to the value that caused an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize)
+}
+
+/// Error returned while serializing or deserializing a value. This includes the path to the value
+/// that failed to convert, e.g `units[2].hp`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    message: String,
+    path: Vec<PathSegment>
+}
+
+impl Error {
+    pub(crate) fn new<T: Display>(message: T) -> Self {
+        Self { message: message.to_string(), path: vec![] }
+    }
+
+    pub(crate) fn at_key(mut self, key: &str) -> Self {
+        self.path.insert(0, PathSegment::Key(key.to_owned()));
+        self
+    }
+
+    pub(crate) fn at_index(mut self, index: usize) -> Self {
+        self.path.insert(0, PathSegment::Index(index));
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    /// Formats the path to the value that caused the error, e.g `units[2].hp`
+    pub fn path_string(&self) -> String {
+        let mut out = String::new();
+        for segment in &self.path {
+            match segment {
+                PathSegment::Key(k) if out.is_empty() => out.push_str(k),
+                PathSegment::Key(k) => { out.push('.'); out.push_str(k) },
+                PathSegment::Index(i) => out.push_str(&format!("[{}]", i))
+            }
+        }
+        out
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path_string(), self.message)
+        }
+    }
+}
+
+impl ::serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl ::serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+/// Serializes the value and pushes it onto the top of the stack. If serialization fails, the stack
+/// is left unchanged. An `f64` that can't be represented exactly by Squirrel's float type (32 bits
+/// unless it's built with SQUSEDOUBLE) is an error rather than being rounded.
+pub fn to_vm<T>(vm: &mut SquirrelVM, value: &T) -> Result<(), Error>
+where T: Serialize + ?Sized {
+    let top = unsafe { sq_gettop(vm.handle) };
+    value.serialize(&mut Serializer::new(vm))
+        .inspect_err(|_| unsafe { sq_settop(vm.handle, top) })
+}
+
+/// Deserializes the value at the given stack index
+pub fn from_vm<T>(vm: &SquirrelVM, index: impl Into<StackIndex>) -> Result<T, Error>
+where T: DeserializeOwned {
+    T::deserialize(Deserializer::new(vm, index))
+}
+
+/// Converts the wrapped value with serde when it's passed to or returned from a Squirrel function,
+/// e.g `vm.call::<_, Serde<Position>>("get_pos", (unit,))` reads the returned table into a
+/// struct. It's meant for reading return values: arguments should be pushed with [`to_vm`] so a
+/// serialization error can be handled. A value that fails to serialize is pushed as null, which
+/// panics in debug builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Serde<T>(pub T);
+
+// the traits aren't imported since String's implementations would shadow String::push
+impl<T> crate::obj_type::SquirrelObject for Serde<T> where T: Serialize + DeserializeOwned {
+    fn push(&self, vm: &mut SquirrelVM) {
+        if let Err(e) = to_vm(vm, &self.0) {
+            debug_assert!(false, "failed to serialize a Serde value: {}", e);
+            unsafe { sq_pushnull(vm.handle) };
+        }
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        from_vm(vm, index).map(Serde).map_err(SquirrelError::Serde)
+    }
+}
+
+impl<T> SquirrelTypeId for Serde<T> {
+    fn type_id() -> u32 {
+        ANY_TYPE_ID
+    }
+}
+
+impl<T> crate::type_cnv::CanSquirrel for Serde<T> where T: Serialize + DeserializeOwned + Clone {
+    type Into = Serde<T>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        <Self::Into as crate::obj_type::SquirrelObject>::push(self, vm);
+    }
+}
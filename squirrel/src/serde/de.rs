@@ -0,0 +1,227 @@
+use ::serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use ::serde::forward_to_deserialize_any;
+use squirrel_sys::bindings::root::*;
+use crate::obj_type::{type_name, StackIndex};
+use crate::serde::Error;
+use crate::table::stack_to_string;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::{SquirrelVM, StackGuard};
+
+/// Deserializer for a single slot on the stack. Any values pushed while reading arrays and tables
+/// are popped before returning.
+#[derive(Clone, Copy)]
+pub struct Deserializer<'a> {
+    vm: &'a SquirrelVM,
+    // absolute stack index, so that pushing values doesn't move it
+    index: SQInteger
+}
+
+impl<'a> Deserializer<'a> {
+    /// Creates a deserializer for the value at the given stack index
+    pub fn new(vm: &'a SquirrelVM, index: impl Into<StackIndex>) -> Self {
+        Self::at(vm, index.into().to_absolute(vm).raw())
+    }
+
+    fn at(vm: &'a SquirrelVM, index: SQInteger) -> Self {
+        Self { vm, index }
+    }
+
+    fn get_type(&self) -> SQObjectType {
+        unsafe { sq_gettype(self.vm.handle, self.index) }
+    }
+
+    fn get<T: CanSquirrel>(&self) -> Result<T::Into, Error> {
+        T::get(self.vm, StackIndex::Absolute(self.index as usize))
+            .map_err(|_| Error::new(format!("could not read {}", type_name(self.get_type()))))
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        #[allow(non_upper_case_globals)]
+        match self.get_type() {
+            tagSQObjectType_OT_NULL => visitor.visit_unit(),
+            tagSQObjectType_OT_BOOL => visitor.visit_bool(self.get::<bool>()? != 0),
+            tagSQObjectType_OT_INTEGER => visitor.visit_i64(self.get::<i64>()?),
+            tagSQObjectType_OT_FLOAT => visitor.visit_f32(self.get::<f32>()?),
+            tagSQObjectType_OT_STRING => visitor.visit_string(self.get::<String>()?),
+            tagSQObjectType_OT_ARRAY => visitor.visit_seq(ArrayAccess::new(self)),
+            tagSQObjectType_OT_TABLE
+            | tagSQObjectType_OT_CLASS
+            | tagSQObjectType_OT_INSTANCE => visitor.visit_map(TableAccess::new(self)),
+            t => Err(Error::new(format!("cannot deserialize a {}", type_name(t))))
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        #[allow(non_upper_case_globals)]
+        match self.get_type() {
+            tagSQObjectType_OT_NULL => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, _: &'static str, visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        #[allow(non_upper_case_globals)]
+        match self.get_type() {
+            tagSQObjectType_OT_STRING => visitor.visit_enum(self.get::<String>()?.into_deserializer()),
+            tagSQObjectType_OT_TABLE => {
+                let len = unsafe { sq_getsize(self.vm.handle, self.index) };
+                if len != 1 {
+                    return Err(Error::new(format!("expected a table with a single slot for an enum variant, found {} slots", len)));
+                }
+                // the table is only used to hold the variant's key and value on the stack
+                let mut access = TableAccess::new(self);
+                access.next()?;
+                visitor.visit_enum(access)
+            },
+            t => Err(Error::new(format!("expected a string or table for an enum, found {}", type_name(t))))
+        }
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+/// Reads each element of an array by pushing it onto the stack
+struct ArrayAccess<'a> {
+    de: Deserializer<'a>,
+    len: usize,
+    pos: usize
+}
+
+impl<'a> ArrayAccess<'a> {
+    fn new(de: Deserializer<'a>) -> Self {
+        let len = unsafe { sq_getsize(de.vm.handle, de.index) } as usize;
+        Self { de, len, pos: 0 }
+    }
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for ArrayAccess<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+    where T: DeserializeSeed<'de> {
+        if self.pos >= self.len {
+            return Ok(None);
+        }
+        let handle = self.de.vm.handle;
+        let guard = self.de.vm.stack_guard();
+        unsafe { sq_pushinteger(handle, self.pos as SQInteger) };
+        if unsafe { sq_get(handle, self.de.index) } != 0 {
+            return Err(Error::new("could not read array element").at_index(self.pos));
+        }
+        let res = seed.deserialize(Deserializer::at(self.de.vm, guard.top() + 1))
+            .map_err(|e| e.at_index(self.pos));
+        self.pos += 1;
+        res.map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.pos)
+    }
+}
+
+/// Reads each slot of a table using sq_next. The iterator, key and value are kept on the stack
+/// until this is dropped.
+struct TableAccess<'a> {
+    de: Deserializer<'a>,
+    guard: StackGuard,
+    key: String
+}
+
+impl<'a> TableAccess<'a> {
+    fn new(de: Deserializer<'a>) -> Self {
+        let guard = de.vm.stack_guard();
+        unsafe { sq_pushnull(de.vm.handle) }; // null iterator
+        Self { de, guard, key: String::new() }
+    }
+
+    // Advances to the next slot, pushing its key and value. Returns false once the end of the
+    // table is reached.
+    fn next(&mut self) -> Result<bool, Error> {
+        let handle = self.de.vm.handle;
+        unsafe { sq_settop(handle, self.guard.top() + 1) };
+        if unsafe { sq_next(handle, self.de.index) } != 0 {
+            return Ok(false);
+        }
+        self.key = stack_to_string(self.de.vm, 2);
+        Ok(true)
+    }
+
+    fn key(&self) -> Deserializer<'a> {
+        Deserializer::at(self.de.vm, self.guard.top() + 2)
+    }
+
+    fn value(&self) -> Deserializer<'a> {
+        Deserializer::at(self.de.vm, self.guard.top() + 3)
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for TableAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Error>
+    where K: DeserializeSeed<'de> {
+        if !self.next()? {
+            return Ok(None);
+        }
+        seed.deserialize(self.key()).map(Some).map_err(|e| e.at_key(&self.key))
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+    where V: DeserializeSeed<'de> {
+        seed.deserialize(self.value()).map_err(|e| e.at_key(&self.key))
+    }
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for TableAccess<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), Error>
+    where V: DeserializeSeed<'de> {
+        let variant = seed.deserialize(self.key()).map_err(|e| e.at_key(&self.key))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for TableAccess<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+    where T: DeserializeSeed<'de> {
+        seed.deserialize(self.value()).map_err(|e| e.at_key(&self.key))
+    }
+
+    fn tuple_variant<V>(self, _: usize, visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        de::Deserializer::deserialize_seq(self.value(), visitor).map_err(|e| e.at_key(&self.key))
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, Error>
+    where V: Visitor<'de> {
+        de::Deserializer::deserialize_map(self.value(), visitor).map_err(|e| e.at_key(&self.key))
+    }
+}
@@ -0,0 +1,354 @@
+use ::serde::ser::{self, Serialize};
+use squirrel_sys::bindings::root::*;
+use crate::serde::Error;
+use crate::table::stack_to_string;
+use crate::vm::SquirrelVM;
+
+fn push_str(vm: &SquirrelVM, value: &str) {
+    unsafe { sq_pushstring(vm.handle, value.as_ptr() as _, value.len() as i64) };
+}
+
+/// Serializer that pushes a single value onto the top of the stack for each call
+pub struct Serializer<'a> {
+    vm: &'a mut SquirrelVM
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(vm: &'a mut SquirrelVM) -> Self {
+        Self { vm }
+    }
+
+    // Pushes a table containing a single slot for the variant, the value is pushed on top of it
+    fn begin_variant(&mut self, variant: &str) {
+        unsafe { sq_newtable(self.vm.handle) };
+        push_str(self.vm, variant);
+    }
+
+    fn end_variant(&mut self, variant: &str) -> Result<(), Error> {
+        match unsafe { sq_newslot(self.vm.handle, -3, false.into()) } {
+            0 => Ok(()),
+            _ => Err(Error::new(format!("could not create slot for variant {}", variant)))
+        }
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, 'b>;
+    type SerializeTuple = Compound<'a, 'b>;
+    type SerializeTupleStruct = Compound<'a, 'b>;
+    type SerializeTupleVariant = Compound<'a, 'b>;
+    type SerializeMap = Compound<'a, 'b>;
+    type SerializeStruct = Compound<'a, 'b>;
+    type SerializeStructVariant = Compound<'a, 'b>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        unsafe { sq_pushbool(self.vm.handle, v.into()) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        unsafe { sq_pushinteger(self.vm.handle, v as SQInteger) };
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => Err(Error::new(format!("integer {} is out of range for a Squirrel integer", v)))
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        unsafe { sq_pushfloat(self.vm.handle, v as SQFloat) };
+        Ok(())
+    }
+
+    // Squirrel floats are 32 bits unless it's built with SQUSEDOUBLE, so values that can't be
+    // represented exactly are rejected rather than silently rounded
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        if !v.is_nan() && v as SQFloat as f64 != v {
+            return Err(Error::new(format!("float {} can't be represented exactly as a Squirrel float", v)));
+        }
+        unsafe { sq_pushfloat(self.vm.handle, v as SQFloat) };
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        push_str(self.vm, v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        unsafe { sq_newarray(self.vm.handle, 0) };
+        for byte in v {
+            unsafe {
+                sq_pushinteger(self.vm.handle, *byte as SQInteger);
+                sq_arrayappend(self.vm.handle, -2);
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        unsafe { sq_pushnull(self.vm.handle) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(self, _: &'static str, _: u32, variant: &'static str, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.begin_variant(variant);
+        value.serialize(&mut *self).map_err(|e| e.at_key(variant))?;
+        self.end_variant(variant)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        unsafe { sq_newarray(self.vm.handle, 0) };
+        Ok(Compound::new(self, None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Error> {
+        self.begin_variant(variant);
+        unsafe { sq_newarray(self.vm.handle, 0) };
+        Ok(Compound::new(self, Some(variant)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        unsafe { sq_newtable(self.vm.handle) };
+        Ok(Compound::new(self, None))
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Error> {
+        self.begin_variant(variant);
+        unsafe { sq_newtable(self.vm.handle) };
+        Ok(Compound::new(self, Some(variant)))
+    }
+}
+
+/// State for serializing arrays and tables. The container being written to is at the top of the
+/// stack, and for enum variants, the outer table and variant key are below it.
+pub struct Compound<'a, 'b> {
+    ser: &'b mut Serializer<'a>,
+    variant: Option<&'static str>,
+    index: usize,
+    key: Option<String>
+}
+
+impl<'a, 'b> Compound<'a, 'b> {
+    fn new(ser: &'b mut Serializer<'a>, variant: Option<&'static str>) -> Self {
+        Self { ser, variant, index: 0, key: None }
+    }
+
+    fn with_path(&self, e: Error) -> Error {
+        match self.variant {
+            Some(v) => e.at_key(v),
+            None => e
+        }
+    }
+
+    fn element<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        let index = self.index;
+        value.serialize(&mut *self.ser).map_err(|e| self.with_path(e.at_index(index)))?;
+        unsafe { sq_arrayappend(self.ser.vm.handle, -2) };
+        self.index += 1;
+        Ok(())
+    }
+
+    fn key<T>(&mut self, key: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        key.serialize(&mut *self.ser).map_err(|e| self.with_path(e))?;
+        self.key = Some(stack_to_string(self.ser.vm, 1));
+        Ok(())
+    }
+
+    fn value<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        let key = self.key.take().unwrap_or_default();
+        value.serialize(&mut *self.ser).map_err(|e| self.with_path(e.at_key(&key)))?;
+        match unsafe { sq_newslot(self.ser.vm.handle, -3, false.into()) } {
+            0 => Ok(()),
+            _ => Err(self.with_path(Error::new(format!("could not create slot for key {}", key))))
+        }
+    }
+
+    fn field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        push_str(self.ser.vm, key);
+        self.key = Some(key.to_owned());
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        match self.variant {
+            Some(variant) => self.ser.end_variant(variant),
+            None => Ok(())
+        }
+    }
+}
+
+impl<'a, 'b> ser::SerializeSeq for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeTuple for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleStruct for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleVariant for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeMap for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.key(key)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeStruct for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for Compound<'a, 'b> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Error>
+    where T: ?Sized + Serialize {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
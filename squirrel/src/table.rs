@@ -0,0 +1,339 @@
+use std::ffi::CString;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::{SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::type_cnv::CanSquirrel;
+use crate::vm::{SquirrelFunction, SquirrelVM, StackGuard};
+
+/// Converts the value at the given stack index into a string using Squirrel's tostring rules.
+/// Used to describe keys in error messages.
+pub(crate) fn stack_to_string(vm: &SquirrelVM, index: usize) -> String {
+    unsafe {
+        if sq_tostring(vm.handle, -(index as i64)) != 0 { return "<unknown>".to_string() }
+        let value = vm.get::<String>(1).unwrap_or_else(|_| "<unknown>".to_string());
+        sq_poptop(vm.handle);
+        value
+    }
+}
+
+/// Pushes a new table containing each key/value pair
+pub(crate) fn push_map<'a, K, V, I>(vm: &mut SquirrelVM, entries: I)
+where K: CanSquirrel + 'a, V: CanSquirrel + 'a, I: Iterator<Item = (&'a K, &'a V)> {
+    unsafe { sq_newtable(vm.handle) };
+    for (key, value) in entries {
+        vm.push(key);
+        vm.push(value);
+        unsafe { sq_newslot(vm.handle, -3, false.into()) };
+    }
+}
+
+/// Reads each slot of the table at the given stack index
+pub(crate) fn get_map<K, V, C>(vm: &SquirrelVM, index: StackIndex) -> Result<C, SquirrelError>
+where K: CanSquirrel, V: CanSquirrel, C: FromIterator<(K, V)> {
+    let table = <SqTable as SquirrelObject>::get(vm, index)?;
+    SqTableIter::new(vm, &table).collect()
+}
+
+/// A handle to a Squirrel table. This can be the root table, const table, registry table or any
+/// table created by a script or from Rust.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SqTable(SqObjectRef);
+
+impl SqTable {
+    /// Creates a new empty table
+    pub fn new(vm: &SquirrelVM) -> Self {
+        let _check = vm.stack_check();
+        unsafe { sq_newtable(vm.handle) };
+        Self(SqObjectRef::take_top(vm))
+    }
+
+    pub fn root(vm: &SquirrelVM) -> Self {
+        let _check = vm.stack_check();
+        unsafe { sq_pushroottable(vm.handle) };
+        Self(SqObjectRef::take_top(vm))
+    }
+
+    pub fn const_table(vm: &SquirrelVM) -> Self {
+        let _check = vm.stack_check();
+        unsafe { sq_pushconsttable(vm.handle) };
+        Self(SqObjectRef::take_top(vm))
+    }
+
+    pub fn registry(vm: &SquirrelVM) -> Self {
+        let _check = vm.stack_check();
+        unsafe { sq_pushregistrytable(vm.handle) };
+        Self(SqObjectRef::take_top(vm))
+    }
+
+    fn key_name<K: CanSquirrel>(vm: &mut SquirrelVM, key: &K) -> String {
+        vm.push(key);
+        let name = stack_to_string(vm, 1);
+        vm.pop_top();
+        name
+    }
+
+    /// Gets the value stored in the slot for the given key
+    pub fn get<K, T>(&self, vm: &mut SquirrelVM, key: &K) -> Result<T, SquirrelError>
+    where K: CanSquirrel, T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(key);
+        match unsafe { sq_get(vm.handle, -2) } {
+            0 => vm.get::<T>(1),
+            _ => Err(SquirrelError::KeyNotFound(Self::key_name(vm, key)))
+        }
+    }
+
+    /// Sets the value of an existing slot. This fails if the slot doesn't exist, use
+    /// [`SqTable::new_slot`] to create one.
+    pub fn set<K, T>(&self, vm: &mut SquirrelVM, key: &K, value: &T) -> Result<(), SquirrelError>
+    where K: CanSquirrel, T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(key);
+        vm.push(value);
+        match unsafe { sq_set(vm.handle, -3) } {
+            0 => Ok(()),
+            _ => Err(SquirrelError::KeyNotFound(Self::key_name(vm, key)))
+        }
+    }
+
+    /// Creates a new slot with the given value, or overwrites the value if it already exists
+    pub fn new_slot<K, T>(&self, vm: &mut SquirrelVM, key: &K, value: &T) -> Result<(), SquirrelError>
+    where K: CanSquirrel, T: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(key);
+        vm.push(value);
+        match unsafe { sq_newslot(vm.handle, -3, false.into()) } {
+            0 => Ok(()),
+            _ => Err(SquirrelError::CouldNotSetSlot(Self::key_name(vm, key)))
+        }
+    }
+
+    /// Deletes the slot for the given key
+    pub fn remove<K>(&self, vm: &mut SquirrelVM, key: &K) -> Result<(), SquirrelError>
+    where K: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(key);
+        match unsafe { sq_deleteslot(vm.handle, -2, false.into()) } {
+            0 => Ok(()),
+            _ => Err(SquirrelError::KeyNotFound(Self::key_name(vm, key)))
+        }
+    }
+
+    pub fn contains<K>(&self, vm: &mut SquirrelVM, key: &K) -> bool
+    where K: CanSquirrel {
+        let _check = vm.stack_check();
+        let _guard = vm.stack_guard();
+        unsafe { self.0.push_raw(vm) };
+        vm.push(key);
+        (unsafe { sq_get(vm.handle, -2) }) == 0
+    }
+
+    /// Returns the number of slots in the table
+    pub fn len(&self, vm: &mut SquirrelVM) -> usize {
+        let _check = vm.stack_check();
+        unsafe {
+            self.0.push_raw(vm);
+            let size = sq_getsize(vm.handle, -1);
+            sq_poptop(vm.handle);
+            size as usize
+        }
+    }
+
+    pub fn is_empty(&self, vm: &mut SquirrelVM) -> bool {
+        self.len(vm) == 0
+    }
+
+    /// Iterates through each key/value pair in the table, converting them into K and V
+    pub fn iter<'a, K, V>(&self, vm: &'a mut SquirrelVM) -> SqTableIter<'a, K, V>
+    where K: CanSquirrel, V: CanSquirrel {
+        SqTableIter::new(vm, &self.0)
+    }
+
+    /// Gets the table in the slot for the given key, or creates it if the slot doesn't exist
+    pub fn get_or_create_table(&self, vm: &mut SquirrelVM, key: &str) -> Result<SqTable, SquirrelError> {
+        let _check = vm.stack_check();
+        let key = key.to_owned();
+        match self.get::<String, SqTable>(vm, &key) {
+            Err(SquirrelError::KeyNotFound(_)) => {
+                let table = SqTable::new(vm);
+                self.new_slot(vm, &key, &table)?;
+                Ok(table)
+            },
+            res => res
+        }
+    }
+
+    /// Adds a native function to the table. The function can capture state, which is dropped once
+    /// Squirrel releases the function. The function can be called again while it's running (e.g
+    /// through a script that it calls), so mutable state should be kept in a `Mutex` or atomic.
+    pub fn add_function<F>(&self, vm: &mut SquirrelVM, name: &str, func: F) -> Result<(), SquirrelError>
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        let _check = vm.stack_check();
+        self.add_function_inner(vm, name, Box::new(func), None)
+    }
+
+    /// Adds a native function to the table, which Squirrel only calls if the number of parameters
+    /// and their types are valid. Otherwise, the script gets an error which can be caught.
+    ///
+    /// nparams includes `this`. A negative value means at least that many parameters, which is
+    /// used for variadic functions (see [`Args`](crate::args::Args)), and 0 skips the check.
+    /// [`PARAMS_FROM_TYPEMASK`](crate::vm::PARAMS_FROM_TYPEMASK) uses the typemask's length. The
+    /// typemask has a character for each parameter (e.g `".n"` for `this` followed by a number),
+    /// see the `sq_setparamscheck` documentation.
+    pub fn add_function_checked<F>(&self, vm: &mut SquirrelVM, name: &str, nparams: SQInteger, typemask: &str, func: F) -> Result<(), SquirrelError>
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        let _check = vm.stack_check();
+        self.add_function_inner(vm, name, Box::new(func), Some((nparams, typemask)))
+    }
+
+    fn add_function_inner(&self, vm: &mut SquirrelVM, name: &str, func: SquirrelFunction, params_check: Option<(SQInteger, &str)>) -> Result<(), SquirrelError> {
+        let _guard = vm.stack_guard();
+        unsafe {
+            self.0.push_raw(vm);
+            sq_pushstring(vm.handle, name.as_ptr() as _, name.len() as _);
+        }
+        vm.push_native_closure(name, func, params_check)
+            .and_then(|_| match unsafe { sq_newslot(vm.handle, -3, false.into()) } {
+                0 => Ok(()),
+                _ => Err(SquirrelError::CouldNotAddFunction)
+            })
+    }
+
+    /// Adds a native function to the table which is called directly by Squirrel
+    ///
+    /// # Safety
+    ///
+    /// `func` is called with the raw VM handle and no checks, so it must follow Squirrel's calling
+    /// convention: it can only access the stack slots of its own call, and must return the number
+    /// of values it pushed (0 or 1) or a negative value after throwing an error. It must not
+    /// unwind, since panics can't cross the C boundary.
+    pub unsafe fn add_function_raw(&self, vm: &mut SquirrelVM, name: &str, func: SQFUNCTION) -> Result<(), SquirrelError> {
+        let _check = vm.stack_check();
+        let cname = CString::new(name).map_err(|_| SquirrelError::CouldNotSetNativeClosureName)?;
+        let _guard = vm.stack_guard();
+        unsafe {
+            self.0.push_raw(vm);
+            sq_pushstring(vm.handle, name.as_ptr() as _, name.len() as _);
+            sq_newclosure(vm.handle, func, 0);
+            match sq_setnativeclosurename(vm.handle, -1, cname.as_ptr()) {
+                0 => match sq_newslot(vm.handle, -3, false.into()) {
+                    0 => Ok(()),
+                    _ => Err(SquirrelError::CouldNotAddFunction)
+                },
+                _ => Err(SquirrelError::CouldNotSetNativeClosureName)
+            }
+        }
+    }
+}
+
+impl Deref for SqTable {
+    type Target = SqObjectRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<SqTable> for SqObjectRef {
+    fn from(value: SqTable) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<SqObjectRef> for SqTable {
+    type Error = SquirrelError;
+
+    fn try_from(value: SqObjectRef) -> Result<Self, Self::Error> {
+        match value.is::<Self>() {
+            true => Ok(Self(value)),
+            false => Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
+    }
+}
+
+impl SquirrelObject for SqTable {
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.0.push_raw(vm) };
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        <SqObjectRef as SquirrelObject>::get(vm, index)?.try_into().map_err(|_| SquirrelError::GetWrongObjectType)
+    }
+}
+
+impl SquirrelTypeId for SqTable {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_TABLE as _
+    }
+}
+
+impl CanSquirrel for SqTable {
+    type Into = SqTable;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "t".to_string()
+    }
+}
+
+/// Iterator over the slots of a table, driven by sq_next. The table and iterator are kept on the
+/// stack until the iterator is dropped.
+pub struct SqTableIter<'a, K, V> {
+    vm: &'a SquirrelVM,
+    // pops the iterator and table
+    _guard: StackGuard,
+    _types: PhantomData<(K, V)>
+}
+
+impl<'a, K, V> SqTableIter<'a, K, V> {
+    pub(crate) fn new(vm: &'a SquirrelVM, obj: &SqObjectRef) -> Self {
+        let _guard = vm.stack_guard();
+        unsafe {
+            obj.push_raw(vm);
+            sq_pushnull(vm.handle); // null iterator
+        }
+        Self { vm, _guard, _types: PhantomData }
+    }
+}
+
+impl<'a, K, V> Iterator for SqTableIter<'a, K, V>
+where K: CanSquirrel, V: CanSquirrel {
+    type Item = Result<(K, V), SquirrelError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if unsafe { sq_next(self.vm.handle, -2) } != 0 {
+            return None;
+        }
+        // value is -1, key is -2
+        let res = self.vm.get::<K>(2)
+            .and_then(|k| self.vm.get::<V>(1).map(|v| (k, v)))
+            .map_err(|e| match e {
+                SquirrelError::GetWrongObjectType => SquirrelError::GetWrongObjectTypeForKey(
+                    stack_to_string(self.vm, 2)),
+                e => e
+            });
+        // pop key and value before next iteration
+        unsafe { sq_pop(self.vm.handle, 2) };
+        Some(res)
+    }
+}
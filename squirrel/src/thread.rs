@@ -0,0 +1,252 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::function::SquirrelArgs;
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::table::SqTable;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::{SquirrelVM, SQ_VMSTATE_IDLE, SQ_VMSTATE_SUSPENDED};
+
+const THREAD_STACK_SIZE: SQInteger = 0x400;
+
+/// The result of running a thread until it suspends or returns
+#[derive(Debug)]
+pub enum ThreadState<T> {
+    /// The thread called `suspend(value)`, and can be resumed with [`SqThread::resume`]
+    Yielded(T),
+    /// The thread's function returned
+    Returned(T),
+    /// The thread threw an error, or the value couldn't be converted into T
+    Error(SquirrelError)
+}
+
+/// A Squirrel thread (coroutine) that runs a closure on its own stack. The closure can call
+/// `suspend(value)` to pause the thread and pass a value back to Rust, which continues once the
+/// thread is resumed.
+#[derive(Debug, Clone)]
+pub struct SqThread {
+    obj: SqObjectRef,
+    closure: SqObjectRef,
+    handle: HSQUIRRELVM
+}
+
+// The thread's handle is only used with the VM that owns it, which is checked before it's started
+// or resumed
+unsafe impl Send for SqThread {}
+
+impl SqThread {
+    /// Creates a thread that runs the given closure once it's started
+    pub fn new(vm: &mut SquirrelVM, closure: &SqObjectRef) -> Result<Self, SquirrelError> {
+        #[allow(non_upper_case_globals)]
+        match closure.get_type() {
+            tagSQObjectType_OT_CLOSURE | tagSQObjectType_OT_NATIVECLOSURE => (),
+            _ => return Err(SquirrelError::ObjectTypeDoesNotMatch)
+        }
+        let handle = unsafe { sq_newthread(vm.handle, THREAD_STACK_SIZE) };
+        Ok(Self { obj: SqObjectRef::take_top(vm), closure: closure.clone(), handle })
+    }
+
+    /// Returns whether the thread is waiting to be resumed. This is false if the thread belongs to
+    /// another VM, or its VM has been closed.
+    pub fn is_suspended(&self, vm: &SquirrelVM) -> bool {
+        self.state(vm) == Some(SQ_VMSTATE_SUSPENDED)
+    }
+
+    // The thread's handle is only valid while the VM that created it is alive
+    fn state(&self, vm: &SquirrelVM) -> Option<SQInteger> {
+        match self.obj.is_owned_by(vm) {
+            true => Some(unsafe { sq_getvmstate(self.handle) }),
+            false => None
+        }
+    }
+
+    /// Calls the closure with the given arguments, with the root table as `this`. This runs until
+    /// the closure suspends, returns or throws an error. Threads can only be started once.
+    pub fn start<Args, R>(&mut self, vm: &mut SquirrelVM, args: Args) -> ThreadState<R>
+    where Args: SquirrelArgs, R: CanSquirrel {
+        match self.state(vm) {
+            Some(SQ_VMSTATE_IDLE) => (),
+            Some(_) => return ThreadState::Error(SquirrelError::ThreadNotIdle),
+            None => return ThreadState::Error(SquirrelError::VMClosed)
+        }
+        self.with_thread(vm, |vm, closure| {
+            unsafe {
+                sq_settop(vm.handle, 0);
+                closure.push_raw(vm);
+                sq_pushroottable(vm.handle);
+            }
+            args.push_args(vm);
+            vm.clear_runtime_error();
+            let res = unsafe { sq_call(vm.handle, (Args::COUNT + 1) as _, true.into(), true.into()) };
+            read_state(vm, res)
+        })
+    }
+
+    /// Resumes a suspended thread, where `value` is returned from the `suspend` call in the script
+    pub fn resume<T, R>(&mut self, vm: &mut SquirrelVM, value: &T) -> ThreadState<R>
+    where T: CanSquirrel, R: CanSquirrel {
+        if !self.is_suspended(vm) {
+            return ThreadState::Error(SquirrelError::ThreadNotSuspended);
+        }
+        self.with_thread(vm, |vm, _| {
+            vm.push(value);
+            vm.clear_runtime_error();
+            let res = unsafe { sq_wakeupvm(vm.handle, true.into(), true.into(), true.into(), false.into()) };
+            read_state(vm, res)
+        })
+    }
+
+    // Runs the function with the thread's handle, so that values are pushed onto the thread's stack
+    fn with_thread<T, F>(&mut self, vm: &mut SquirrelVM, f: F) -> T
+    where F: FnOnce(&mut SquirrelVM, &SqObjectRef) -> T {
+        let handle = std::mem::replace(&mut vm.handle, self.handle);
+        let guard = ThreadHandleGuard { vm, handle };
+        f(guard.vm, &self.closure)
+    }
+}
+
+// Restores the VM's handle once the thread stops running, including when the callback panics
+struct ThreadHandleGuard<'a> {
+    vm: &'a mut SquirrelVM,
+    handle: HSQUIRRELVM
+}
+
+impl<'a> Drop for ThreadHandleGuard<'a> {
+    fn drop(&mut self) {
+        self.vm.handle = self.handle;
+    }
+}
+
+// Returns whether the thread is suspended. The value that the thread suspended or returned with is
+// left at the top of its stack.
+fn check_result(vm: &mut SquirrelVM, res: SQRESULT) -> Result<bool, SquirrelError> {
+    if res != 0 {
+        let err = vm.take_runtime_error();
+        unsafe { sq_settop(vm.handle, 0) };
+        return Err(err);
+    }
+    Ok(unsafe { sq_getvmstate(vm.handle) } == SQ_VMSTATE_SUSPENDED)
+}
+
+// Pops the value that the thread suspended or returned with. The thread's stack is cleared once it
+// stops running.
+fn take_value<R: CanSquirrel>(vm: &mut SquirrelVM, suspended: bool) -> Result<R, SquirrelError> {
+    let value = vm.get::<R>(1);
+    unsafe {
+        match suspended {
+            true => sq_poptop(vm.handle),
+            false => sq_settop(vm.handle, 0)
+        }
+    }
+    value
+}
+
+fn read_state<R: CanSquirrel>(vm: &mut SquirrelVM, res: SQRESULT) -> ThreadState<R> {
+    match check_result(vm, res) {
+        Ok(true) => take_value(vm, true).map_or_else(ThreadState::Error, ThreadState::Yielded),
+        Ok(false) => take_value(vm, false).map_or_else(ThreadState::Error, ThreadState::Returned),
+        Err(e) => ThreadState::Error(e)
+    }
+}
+
+impl Deref for SqThread {
+    type Target = SqObjectRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.obj
+    }
+}
+
+impl From<SqThread> for SqObjectRef {
+    fn from(value: SqThread) -> Self {
+        value.obj
+    }
+}
+
+impl SquirrelTypeId for SqThread {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_THREAD as _
+    }
+}
+
+// Iterates over a generator from a thread which suspends with each value, since the generator's
+// state can't be read through the API
+const GENERATOR_ITER_SOURCE: &str = "foreach (v in vargv[0]) ::suspend(v);";
+
+/// Iterates over the values yielded by a Squirrel generator. Iteration stops once the generator
+/// returns, or after the first error.
+pub struct SqGeneratorIter<'a, T> {
+    vm: &'a mut SquirrelVM,
+    thread: SqThread,
+    generator: Option<SqObjectRef>,
+    done: bool,
+    _type: PhantomData<T>
+}
+
+impl<'a, T> SqGeneratorIter<'a, T>
+where T: CanSquirrel {
+    pub fn new(vm: &'a mut SquirrelVM, generator: &SqObjectRef) -> Result<Self, SquirrelError> {
+        if generator.get_type() != tagSQObjectType_OT_GENERATOR {
+            return Err(SquirrelError::ObjectTypeDoesNotMatch);
+        }
+        let closure = Self::iter_closure(vm)?;
+        let thread = SqThread::new(vm, &closure)?;
+        Ok(Self { vm, thread, generator: Some(generator.clone()), done: false, _type: PhantomData })
+    }
+
+    // The helper closure is compiled once per VM, and kept in the registry
+    fn iter_closure(vm: &mut SquirrelVM) -> Result<SqObjectRef, SquirrelError> {
+        let registry = SqTable::registry(vm);
+        let key = "sqcrab.generator_iter".to_string();
+        match registry.get::<String, SqObjectRef>(vm, &key) {
+            Err(SquirrelError::KeyNotFound(_)) => {
+                vm.try_compile(GENERATOR_ITER_SOURCE, "generator")?;
+                let closure = SqObjectRef::take_top(vm);
+                registry.new_slot(vm, &key, &closure)?;
+                Ok(closure)
+            },
+            res => res
+        }
+    }
+}
+
+impl<'a, T> Iterator for SqGeneratorIter<'a, T>
+where T: CanSquirrel {
+    type Item = Result<T, SquirrelError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None }
+        let generator = self.generator.take();
+        let (value, done) = self.thread.with_thread(self.vm, |vm, closure| {
+            vm.clear_runtime_error();
+            let res = match generator {
+                Some(generator) => unsafe {
+                    sq_settop(vm.handle, 0);
+                    closure.push_raw(vm);
+                    sq_pushroottable(vm.handle);
+                    generator.push_raw(vm);
+                    sq_call(vm.handle, 2, true.into(), true.into())
+                },
+                None => unsafe {
+                    sq_pushnull(vm.handle);
+                    sq_wakeupvm(vm.handle, true.into(), true.into(), true.into(), false.into())
+                }
+            };
+            match check_result(vm, res) {
+                Ok(true) => {
+                    let value = take_value::<T>(vm, true);
+                    let done = value.is_err();
+                    (Some(value), done)
+                },
+                Ok(false) => {
+                    unsafe { sq_settop(vm.handle, 0) };
+                    (None, true)
+                },
+                Err(e) => (Some(Err(e)), true)
+            }
+        });
+        self.done = done;
+        value
+    }
+}
@@ -1,6 +1,8 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 use squirrel_sys::bindings::root::*;
 use crate::err::SquirrelError;
-use crate::obj_type::SquirrelObject;
+use crate::obj_type::{SquirrelObject, StackIndex};
 use crate::vm::SquirrelVM;
 
 pub trait CanSquirrel where Self: Sized {
@@ -16,9 +18,15 @@ pub trait CanSquirrel where Self: Sized {
         <Self::Into>::push(&self.into_squirrel(), vm);
     }
 
-    fn get(vm: &SquirrelVM, index: usize) -> Result<Self::Into, SquirrelError> {
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self::Into, SquirrelError> {
         <Self::Into>::get(vm, index)
     }
+
+    /// The typemask used by `sq_setparamscheck` to check parameters of this type, e.g `"n"` for
+    /// numbers. Types that can be any Squirrel object use `"."`.
+    fn type_mask() -> String {
+        ".".to_string()
+    }
 }
 
 macro_rules! auto_sq_object {
@@ -35,6 +43,10 @@ macro_rules! auto_sq_object {
             fn from_squirrel(v: Self::Into) -> Self {
                 v as _
             }
+
+            fn type_mask() -> String {
+                "n".to_string()
+            }
         }
     };
 
@@ -60,6 +72,10 @@ impl CanSquirrel for bool {
     fn from_squirrel(v: Self::Into) -> Self {
         v != 0
     }
+
+    fn type_mask() -> String {
+        "b".to_string()
+    }
 }
 
 impl CanSquirrel for () {
@@ -74,6 +90,47 @@ impl CanSquirrel for () {
     fn from_squirrel(v: Self::Into) -> Self {
         v
     }
+
+    fn type_mask() -> String {
+        "o".to_string()
+    }
+}
+
+impl<T> CanSquirrel for Option<T> where T: CanSquirrel {
+    type Into = Option<T::Into>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.as_ref().map(|v| v.into_squirrel())
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v.map(|v| T::from_squirrel(v))
+    }
+
+    fn type_mask() -> String {
+        match T::type_mask() {
+            m if m == "." => m,
+            m => format!("{}|o", m)
+        }
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        match self {
+            Some(v) => v.push(vm),
+            None => vm.push(&())
+        }
+    }
+
+    // read through T's CanSquirrel impl so that it's used for non null values
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self::Into, SquirrelError> {
+        #[allow(non_upper_case_globals)]
+        match unsafe { sq_gettype(vm.handle, index.raw()) } {
+            tagSQObjectType_OT_NULL => Ok(None),
+            _ => <T as CanSquirrel>::get(vm, index).map(Some)
+        }
+    }
 }
 
 impl CanSquirrel for String {
@@ -88,4 +145,157 @@ impl CanSquirrel for String {
     fn from_squirrel(v: Self::Into) -> Self {
         v
     }
+
+    fn type_mask() -> String {
+        "s".to_string()
+    }
+}
+
+impl<T> CanSquirrel for Vec<T> where T: CanSquirrel + Clone {
+    type Into = Vec<T>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "a".to_string()
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        <Self::Into as SquirrelObject>::push(self, vm);
+    }
+}
+
+impl<T, const N: usize> CanSquirrel for [T; N] where T: CanSquirrel + Clone {
+    type Into = [T; N];
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "a".to_string()
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        <Self::Into as SquirrelObject>::push(self, vm);
+    }
+}
+
+impl<'a, T> CanSquirrel for &'a [T] where T: CanSquirrel {
+    type Into = &'a [T];
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        *self
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "a".to_string()
+    }
+}
+
+macro_rules! tuple_can_squirrel {
+    ($($name:ident),*) => {
+        impl<$($name),*> CanSquirrel for ($($name,)*) where $($name: CanSquirrel + Clone),* {
+            type Into = ($($name,)*);
+
+            const RETURNS: bool = true;
+
+            fn into_squirrel(&self) -> Self::Into {
+                self.clone()
+            }
+
+            fn from_squirrel(v: Self::Into) -> Self {
+                v
+            }
+
+            fn type_mask() -> String {
+                "a".to_string()
+            }
+
+            fn push(&self, vm: &mut SquirrelVM) {
+                <Self::Into as SquirrelObject>::push(self, vm);
+            }
+        }
+    };
+}
+
+tuple_can_squirrel!(A);
+tuple_can_squirrel!(A, B);
+tuple_can_squirrel!(A, B, C);
+tuple_can_squirrel!(A, B, C, D);
+tuple_can_squirrel!(A, B, C, D, E);
+tuple_can_squirrel!(A, B, C, D, E, F);
+tuple_can_squirrel!(A, B, C, D, E, F, G);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K, L);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O);
+tuple_can_squirrel!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+impl<K, V> CanSquirrel for HashMap<K, V> where K: CanSquirrel + Clone + Eq + Hash, V: CanSquirrel + Clone {
+    type Into = HashMap<K, V>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "t".to_string()
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        <Self::Into as SquirrelObject>::push(self, vm);
+    }
+}
+
+impl<K, V> CanSquirrel for BTreeMap<K, V> where K: CanSquirrel + Clone + Ord, V: CanSquirrel + Clone {
+    type Into = BTreeMap<K, V>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "t".to_string()
+    }
+
+    fn push(&self, vm: &mut SquirrelVM) {
+        <Self::Into as SquirrelObject>::push(self, vm);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use squirrel_sys::bindings::root::*;
+use crate::err::SquirrelError;
+use crate::obj_type::{check_type_tag, type_tag, SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId};
+use crate::type_cnv::CanSquirrel;
+use crate::vm::SquirrelVM;
+
+// The userdata block holds a pointer to the shared value, so that T's alignment doesn't depend on
+// Squirrel's allocator. Squirrel owns one strong reference, which is released here.
+unsafe extern "C" fn release_userdata<T>(p: SQUserPointer, _: SQInteger) -> SQInteger {
+    let value = unsafe { *(p as *mut *const Mutex<T>) };
+    if !value.is_null() {
+        drop(unsafe { Arc::from_raw(value) });
+    }
+    1
+}
+
+/// A Rust value owned by the Squirrel VM. The value is moved into a userdata object which is tagged
+/// with T's type tag, and is dropped once the userdata is released by Squirrel and no handles are
+/// left. Handles hold a reference to the userdata, so the value stays alive for as long as either
+/// Rust or a script uses it.
+///
+/// Every handle to the same userdata shares the value, which is accessed through
+/// [`UserData::borrow`] and [`UserData::borrow_mut`]. These fail once the VM has been closed. The
+/// value is behind a mutex and needs to be `Send`, since the VM can drop its reference on another
+/// thread.
+pub struct UserData<T: Send + 'static> {
+    obj: SqObjectRef,
+    value: Arc<Mutex<T>>
+}
+
+impl<T: Send + 'static> UserData<T> {
+    pub fn new(vm: &SquirrelVM, value: T) -> Self {
+        let value = Arc::new(Mutex::new(value));
+        unsafe {
+            let p = sq_newuserdata(vm.handle, size_of::<*const Mutex<T>>() as _) as *mut *const Mutex<T>;
+            *p = Arc::into_raw(value.clone());
+            sq_settypetag(vm.handle, -1, type_tag::<T>());
+            sq_setreleasehook(vm.handle, -1, Some(release_userdata::<T>));
+        }
+        Self { obj: SqObjectRef::take_top(vm), value }
+    }
+
+    /// Borrows the value for reading. This fails if the value is already borrowed or the VM has
+    /// been closed.
+    pub fn borrow(&self) -> Result<MutexGuard<'_, T>, SquirrelError> {
+        self.lock()
+    }
+
+    /// Borrows the value for writing. This fails if the value is already borrowed or the VM has
+    /// been closed.
+    pub fn borrow_mut(&self) -> Result<MutexGuard<'_, T>, SquirrelError> {
+        self.lock()
+    }
+
+    // A borrow that panicked doesn't stop the value from being used again, as with a RefCell
+    fn lock(&self) -> Result<MutexGuard<'_, T>, SquirrelError> {
+        if !self.obj.is_alive() { return Err(SquirrelError::VMClosed) }
+        match self.value.try_lock() {
+            Ok(v) => Ok(v),
+            Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+            Err(TryLockError::WouldBlock) => Err(SquirrelError::AlreadyBorrowed)
+        }
+    }
+}
+
+impl<T: Send + 'static> Clone for UserData<T> {
+    fn clone(&self) -> Self {
+        Self { obj: self.obj.clone(), value: self.value.clone() }
+    }
+}
+
+impl<T: Debug + Send + 'static> Debug for UserData<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.borrow() {
+            Ok(v) => f.debug_tuple("UserData").field(&*v).finish(),
+            Err(e) => f.debug_tuple("UserData").field(&e).finish()
+        }
+    }
+}
+
+impl<T: Send + 'static> From<UserData<T>> for SqObjectRef {
+    fn from(value: UserData<T>) -> Self {
+        value.obj
+    }
+}
+
+impl<T: Send + 'static> SquirrelObject for UserData<T> {
+    fn push(&self, vm: &mut SquirrelVM) {
+        unsafe { self.obj.push_raw(vm) };
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        let mut p: SQUserPointer = std::ptr::null_mut();
+        let mut tag: SQUserPointer = std::ptr::null_mut();
+        let res = unsafe { sq_getuserdata(vm.handle, index.raw(), &mut p, &mut tag) };
+        if res != 0 { return Err(SquirrelError::GetWrongObjectType) }
+        check_type_tag::<T>(vm.handle, index.raw(), tag)?;
+        let value = unsafe { *(p as *mut *const Mutex<T>) };
+        if value.is_null() { return Err(SquirrelError::ObjectTypeDoesNotMatch) }
+        // the userdata keeps its own reference, so this one needs to be counted separately
+        let value = unsafe {
+            Arc::increment_strong_count(value);
+            Arc::from_raw(value)
+        };
+        Ok(Self { obj: <SqObjectRef as SquirrelObject>::get(vm, index)?, value })
+    }
+}
+
+impl<T: Send + 'static> SquirrelTypeId for UserData<T> {
+    fn type_id() -> u32 {
+        tagSQObjectType_OT_USERDATA as _
+    }
+}
+
+impl<T: Send + 'static> CanSquirrel for UserData<T> {
+    type Into = UserData<T>;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+
+    fn type_mask() -> String {
+        "u".to_string()
+    }
+}
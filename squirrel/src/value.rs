@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use squirrel_sys::bindings::root::*;
+use crate::array::SqArray;
+use crate::class::SqClass;
+use crate::err::SquirrelError;
+use crate::obj_type::{type_name, SquirrelObject, StackIndex};
+use crate::object::{SqObjectRef, SquirrelTypeId, ANY_TYPE_ID};
+use crate::table::SqTable;
+use crate::type_cnv::CanSquirrel;
+use crate::vm::SquirrelVM;
+
+/// Any Squirrel value, for reading values whose type isn't known ahead of time (e.g the arguments
+/// of a variadic function). Primitive values are copied, while other objects are held as handles.
+///
+/// Comparing with `==` compares objects by identity. Use [`SqValue::deep_eq`] to compare the
+/// contents of tables and arrays.
+#[derive(Clone, PartialEq)]
+pub enum SqValue {
+    Null,
+    Integer(SQInteger),
+    Float(SQFloat),
+    Bool(bool),
+    String(String),
+    Table(SqTable),
+    Array(SqArray),
+    Closure(SqObjectRef),
+    NativeClosure(SqObjectRef),
+    Class(SqClass),
+    Instance(SqObjectRef),
+    UserData(SqObjectRef),
+    UserPointer(SQUserPointer),
+    Generator(SqObjectRef),
+    Thread(SqObjectRef),
+    WeakRef(SqObjectRef)
+}
+
+impl SqValue {
+    pub fn get_type(&self) -> SQObjectType {
+        match self {
+            Self::Null => tagSQObjectType_OT_NULL,
+            Self::Integer(_) => tagSQObjectType_OT_INTEGER,
+            Self::Float(_) => tagSQObjectType_OT_FLOAT,
+            Self::Bool(_) => tagSQObjectType_OT_BOOL,
+            Self::String(_) => tagSQObjectType_OT_STRING,
+            Self::Table(_) => tagSQObjectType_OT_TABLE,
+            Self::Array(_) => tagSQObjectType_OT_ARRAY,
+            Self::Closure(_) => tagSQObjectType_OT_CLOSURE,
+            Self::NativeClosure(_) => tagSQObjectType_OT_NATIVECLOSURE,
+            Self::Class(_) => tagSQObjectType_OT_CLASS,
+            Self::Instance(_) => tagSQObjectType_OT_INSTANCE,
+            Self::UserData(_) => tagSQObjectType_OT_USERDATA,
+            Self::UserPointer(_) => tagSQObjectType_OT_USERPOINTER,
+            Self::Generator(_) => tagSQObjectType_OT_GENERATOR,
+            Self::Thread(_) => tagSQObjectType_OT_THREAD,
+            Self::WeakRef(_) => tagSQObjectType_OT_WEAKREF
+        }
+    }
+
+    /// The name of the value's type, as returned by `typeof` in a script
+    pub fn type_name(&self) -> &'static str {
+        type_name(self.get_type())
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    // The handle of reference counted objects, used to track objects that were already visited
+    fn object(&self) -> Option<&SqObjectRef> {
+        match self {
+            Self::Table(v) => Some(v),
+            Self::Array(v) => Some(v),
+            Self::Class(v) => Some(v),
+            Self::Closure(v) | Self::NativeClosure(v) | Self::Instance(v) | Self::UserData(v) |
+            Self::Generator(v) | Self::Thread(v) | Self::WeakRef(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Copies the value, creating new tables and arrays for any nested inside of it. Other
+    /// objects such as closures and instances are shared with the original. Tables that contain
+    /// themselves are copied into tables that contain the copy.
+    pub fn deep_clone(&self, vm: &mut SquirrelVM) -> Result<Self, SquirrelError> {
+        self.deep_clone_inner(vm, &mut HashMap::new())
+    }
+
+    fn deep_clone_inner(&self, vm: &mut SquirrelVM, copies: &mut HashMap<ObjectKey, SqValue>) -> Result<Self, SquirrelError> {
+        if let Some(copy) = self.object().and_then(|o| copies.get(&object_key(o))) {
+            return Ok(copy.clone());
+        }
+        match self {
+            Self::Table(table) => {
+                let copy = SqTable::new(vm);
+                copies.insert(object_key(table), Self::Table(copy.clone()));
+                let slots = table.iter::<SqValue, SqValue>(vm).collect::<Result<Vec<_>, _>>()?;
+                for (key, value) in slots {
+                    let value = value.deep_clone_inner(vm, copies)?;
+                    copy.new_slot(vm, &key, &value)?;
+                }
+                Ok(Self::Table(copy))
+            },
+            Self::Array(array) => {
+                let copy = SqArray::new(vm, 0);
+                copies.insert(object_key(array), Self::Array(copy.clone()));
+                let values = array.iter::<SqValue>(vm).collect::<Result<Vec<_>, _>>()?;
+                for value in values {
+                    let value = value.deep_clone_inner(vm, copies)?;
+                    copy.append(vm, &value)?;
+                }
+                Ok(Self::Array(copy))
+            },
+            v => Ok(v.clone())
+        }
+    }
+
+    /// Compares the contents of tables and arrays, rather than their identity. Tables are equal if
+    /// they have the same keys, where each value is deeply equal. Other objects are compared by
+    /// identity.
+    pub fn deep_eq(&self, vm: &mut SquirrelVM, other: &Self) -> Result<bool, SquirrelError> {
+        self.deep_eq_inner(vm, other, &mut HashSet::new())
+    }
+
+    fn deep_eq_inner(&self, vm: &mut SquirrelVM, other: &Self, visited: &mut HashSet<(ObjectKey, ObjectKey)>) -> Result<bool, SquirrelError> {
+        if self == other { return Ok(true) }
+        // a pair that's already being compared is equal unless a difference is found elsewhere
+        if let (Some(a), Some(b)) = (self.object(), other.object())
+            && !visited.insert((object_key(a), object_key(b))) {
+            return Ok(true)
+        }
+        match (self, other) {
+            (Self::Table(a), Self::Table(b)) => {
+                if a.len(vm) != b.len(vm) { return Ok(false) }
+                let slots = a.iter::<SqValue, SqValue>(vm).collect::<Result<Vec<_>, _>>()?;
+                for (key, value) in slots {
+                    match raw_get(vm, b, &key) {
+                        Some(other) if value.deep_eq_inner(vm, &other, visited)? => (),
+                        _ => return Ok(false)
+                    }
+                }
+                Ok(true)
+            },
+            (Self::Array(a), Self::Array(b)) => {
+                if a.len(vm) != b.len(vm) { return Ok(false) }
+                let values = a.iter::<SqValue>(vm).zip(b.iter::<SqValue>(vm))
+                    .map(|(a, b)| a.and_then(|a| b.map(|b| (a, b))))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (a, b) in values {
+                    if !a.deep_eq_inner(vm, &b, visited)? { return Ok(false) }
+                }
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+}
+
+// Identifies an object by its type and pointer while it's being walked. The objects are kept alive
+// by the values being cloned or compared, so a pointer can't be reused during the walk.
+type ObjectKey = (SQObjectType, SQRawObjectVal);
+
+fn object_key(obj: &SqObjectRef) -> ObjectKey {
+    let raw = unsafe { obj.raw() };
+    (raw._type, unsafe { raw._unVal.raw })
+}
+
+// Reads a slot without calling the table's delegate, which would find default methods such as len
+fn raw_get(vm: &mut SquirrelVM, table: &SqTable, key: &SqValue) -> Option<SqValue> {
+    let _guard = vm.stack_guard();
+    unsafe { table.push_raw(vm) };
+    vm.push(key);
+    match unsafe { sq_rawget(vm.handle, -2) } {
+        0 => vm.get::<SqValue>(1).ok(),
+        _ => None
+    }
+}
+
+impl Debug for SqValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Integer(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{:?}", v),
+            Self::Bool(v) => write!(f, "{}", v),
+            Self::String(v) => write!(f, "{:?}", v),
+            Self::UserPointer(p) => write!(f, "userpointer({:?})", p),
+            v => {
+                let obj = v.object().unwrap();
+                write!(f, "{}(0x{:x})", v.type_name(), unsafe { obj.raw()._unVal.raw })
+            }
+        }
+    }
+}
+
+impl SquirrelObject for SqValue {
+    fn push(&self, vm: &mut SquirrelVM) {
+        match self {
+            Self::Null => unsafe { sq_pushnull(vm.handle) },
+            Self::Integer(v) => SquirrelObject::push(v, vm),
+            Self::Float(v) => SquirrelObject::push(v, vm),
+            Self::Bool(v) => unsafe { sq_pushbool(vm.handle, (*v).into()) },
+            Self::String(v) => SquirrelObject::push(v, vm),
+            Self::Table(v) => SquirrelObject::push(v, vm),
+            Self::Array(v) => SquirrelObject::push(v, vm),
+            Self::Class(v) => SquirrelObject::push(v, vm),
+            Self::UserPointer(p) => unsafe { sq_pushuserpointer(vm.handle, *p) },
+            Self::Closure(v) | Self::NativeClosure(v) | Self::Instance(v) | Self::UserData(v) |
+            Self::Generator(v) | Self::Thread(v) | Self::WeakRef(v) => SquirrelObject::push(v, vm)
+        }
+    }
+
+    fn get(vm: &SquirrelVM, index: StackIndex) -> Result<Self, SquirrelError> {
+        #[allow(non_upper_case_globals)]
+        Ok(match unsafe { sq_gettype(vm.handle, index.raw()) } {
+            tagSQObjectType_OT_NULL => Self::Null,
+            tagSQObjectType_OT_INTEGER => Self::Integer(<SQInteger as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_FLOAT => Self::Float(<SQFloat as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_BOOL => Self::Bool(<SQBool as SquirrelObject>::get(vm, index)? != 0),
+            tagSQObjectType_OT_STRING => Self::String(<String as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_TABLE => Self::Table(<SqTable as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_ARRAY => Self::Array(<SqArray as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_CLASS => Self::Class(<SqClass as SquirrelObject>::get(vm, index)?),
+            tagSQObjectType_OT_USERPOINTER => {
+                let mut p: SQUserPointer = std::ptr::null_mut();
+                if unsafe { sq_getuserpointer(vm.handle, index.raw(), &mut p) } != 0 {
+                    return Err(SquirrelError::GetWrongObjectType);
+                }
+                Self::UserPointer(p)
+            },
+            ty => {
+                let obj = <SqObjectRef as SquirrelObject>::get(vm, index)?;
+                match ty {
+                    tagSQObjectType_OT_CLOSURE => Self::Closure(obj),
+                    tagSQObjectType_OT_NATIVECLOSURE => Self::NativeClosure(obj),
+                    tagSQObjectType_OT_INSTANCE => Self::Instance(obj),
+                    tagSQObjectType_OT_USERDATA => Self::UserData(obj),
+                    tagSQObjectType_OT_GENERATOR => Self::Generator(obj),
+                    tagSQObjectType_OT_THREAD => Self::Thread(obj),
+                    tagSQObjectType_OT_WEAKREF => Self::WeakRef(obj),
+                    _ => return Err(SquirrelError::GetWrongObjectType)
+                }
+            }
+        })
+    }
+}
+
+impl SquirrelTypeId for SqValue {
+    fn type_id() -> u32 {
+        ANY_TYPE_ID
+    }
+}
+
+impl CanSquirrel for SqValue {
+    type Into = SqValue;
+
+    const RETURNS: bool = true;
+
+    fn into_squirrel(&self) -> Self::Into {
+        self.clone()
+    }
+
+    fn from_squirrel(v: Self::Into) -> Self {
+        v
+    }
+}
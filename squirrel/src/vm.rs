@@ -1,19 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::error::Error;
+use std::ffi::CString;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::io::{Cursor, Read};
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
-use std::ops::{Deref, DerefMut};
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use std::ptr::NonNull;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use squirrel_sys::bindings::root::*;
+use crate::class::{set_instance_up, SqClassBuilder, SquirrelClass};
 use crate::err::SquirrelError;
-use crate::obj_type::{SquirrelObject, UserPointer};
-use crate::object::SquirrelTypeId;
+use crate::function::{SqFunction, SquirrelArgs};
+use crate::obj_type::{stack_type_name, type_tag, SquirrelObject, StackIndex, UserPointer};
+use crate::object::{SqObjectRef, SquirrelTypeId};
 use crate::squirrel;
+use crate::table::{SqTable, SqTableIter};
 use crate::type_cnv::CanSquirrel;
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -32,107 +36,148 @@ impl Hash for ThreadSafeSquirrelVMPointer {
 unsafe impl Send for ThreadSafeSquirrelVMPointer {}
 unsafe impl Sync for ThreadSafeSquirrelVMPointer {}
 
-// Returns the number of parameters that were pushed (sq_pushx)
-type SquirrelFunction = fn(&mut SquirrelVM) -> SQInteger;
-
-pub struct SQVMNativeFunctionLink {
-    pub(crate) sqvm: NonNull<SquirrelVM>,
-    pub(crate) functions: HashMap<String, SquirrelFunction>
+// Shared between a SquirrelVM and the object handles created from it, so that handles which outlive
+// the VM don't try to release their reference into a closed VM
+pub(crate) struct SquirrelVMOwner {
+    pub(crate) handle: HSQUIRRELVM,
+    pub(crate) alive: AtomicBool,
+    // set by the runtime error handler, and taken once the failed call returns
+    pub(crate) last_error: Mutex<Option<SquirrelError>>,
+    // objects whose last handle was dropped. Handles can be dropped on any thread, so they're
+    // released by the next call into the VM from the thread that owns it.
+    pub(crate) released: Mutex<Vec<HSQOBJECT>>,
+    pub(crate) has_released: AtomicBool
 }
 
-impl SQVMNativeFunctionLink {
-    pub(crate) fn new(handle: &mut SquirrelVM) -> Self {
-        let handle = unsafe { NonNull::new_unchecked(&raw mut *handle) };
-        Self { sqvm: handle, functions: HashMap::new() }
+impl SquirrelVMOwner {
+    fn new(handle: HSQUIRRELVM) -> Self {
+        Self {
+            handle,
+            alive: AtomicBool::new(true),
+            last_error: Mutex::new(None),
+            released: Mutex::new(vec![]),
+            has_released: AtomicBool::new(false)
+        }
     }
 }
 
-impl Deref for SQVMNativeFunctionLink {
-    type Target = SquirrelVM;
-    fn deref(&self) -> &Self::Target {
-        unsafe { self.sqvm.as_ref() }
+impl Debug for SquirrelVMOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SquirrelVMOwner")
+            .field("handle", &self.handle)
+            .field("alive", &self.alive)
+            .field("last_error", &self.last_error)
+            .finish_non_exhaustive()
     }
 }
 
-impl DerefMut for SQVMNativeFunctionLink {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { self.sqvm.as_mut() }
-    }
+unsafe impl Send for SquirrelVMOwner {}
+unsafe impl Sync for SquirrelVMOwner {}
+
+/// Restores the stack to the size it had when the guard was created, once the guard is dropped.
+/// This cleans up values that were pushed in a scope on every exit path, including early returns
+/// for errors. Created with [`SquirrelVM::stack_guard`].
+///
+/// In debug builds, dropping the guard asserts that the scope didn't pop values that were pushed
+/// before the guard was created, since the stack can't be restored in that case. Nothing is
+/// restored if the VM has been closed before the guard is dropped.
+pub struct StackGuard {
+    owner: Arc<SquirrelVMOwner>,
+    handle: HSQUIRRELVM,
+    top: SQInteger,
+    #[cfg(debug_assertions)]
+    location: &'static std::panic::Location<'static>
 }
 
-impl Hash for SQVMNativeFunctionLink {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        (self.sqvm.as_ptr() as usize).hash(state)
+impl StackGuard {
+    /// The size of the stack when the guard was created
+    pub fn top(&self) -> SQInteger {
+        self.top
     }
 }
-unsafe impl Send for SQVMNativeFunctionLink {}
-unsafe impl Sync for SQVMNativeFunctionLink {}
-
-// Used to obtain a reference to SquirrelVM from the HSQUIRRELVM ptr in SQFUNCTION
-
-type SquirrelHandleMap = HashMap<ThreadSafeSquirrelVMPointer, SQVMNativeFunctionLink>;
 
-static SQUIRREL_HANDLE_INSTANCES: Mutex<Option<SquirrelHandleMap>> = Mutex::new(None);
-
-pub(crate) fn get_squirrel_handle_instances() -> MutexGuard<'static, Option<SquirrelHandleMap>> {
-    let mut instances = SQUIRREL_HANDLE_INSTANCES.lock().unwrap();
-    if instances.is_none() {
-        *instances = Some(HashMap::new());
+impl Drop for StackGuard {
+    fn drop(&mut self) {
+        // the handle is freed once the VM is closed
+        if !self.owner.alive.load(Ordering::Acquire) {
+            return;
+        }
+        #[cfg(debug_assertions)]
+        if !std::thread::panicking() {
+            let top = unsafe { sq_gettop(self.handle) };
+            debug_assert!(top >= self.top, "stack guard created at {} was popped below its starting size ({} < {})",
+                          self.location, top, self.top);
+        }
+        unsafe { sq_settop(self.handle, self.top) };
     }
-    instances
-}
-
-pub unsafe fn add_squirrel_handle(vm: &mut SquirrelVM) {
-    get_squirrel_handle_instances().as_mut().unwrap().insert(
-        ThreadSafeSquirrelVMPointer(vm.handle),
-        SQVMNativeFunctionLink::new(vm)
-    );
 }
 
-pub unsafe fn check_squirrel_handle(vm: &SquirrelVM) -> bool {
-    get_squirrel_handle_instances().as_ref().unwrap()
-        .contains_key(&ThreadSafeSquirrelVMPointer(vm.handle))
+// Checks that a public API call leaves the stack at the size it had when the call started. This
+// only does anything in debug builds, where dropping it panics if values were leaked or popped.
+#[doc(hidden)]
+pub struct StackCheck {
+    #[cfg(debug_assertions)]
+    owner: Arc<SquirrelVMOwner>,
+    #[cfg(debug_assertions)]
+    handle: HSQUIRRELVM,
+    #[cfg(debug_assertions)]
+    top: SQInteger,
+    #[cfg(debug_assertions)]
+    location: &'static std::panic::Location<'static>
 }
 
-pub unsafe fn from_handle(vm: HSQUIRRELVM) -> Option<&'static SquirrelVM> {
-    get_squirrel_handle_instances().as_ref().unwrap()
-        .get(&ThreadSafeSquirrelVMPointer(vm))
-        .map(|link| unsafe { link.sqvm.as_ref() })
+#[cfg(debug_assertions)]
+impl Drop for StackCheck {
+    fn drop(&mut self) {
+        if std::thread::panicking() || !self.owner.alive.load(Ordering::Acquire) {
+            return;
+        }
+        let top = unsafe { sq_gettop(self.handle) };
+        assert_eq!(top, self.top, "the call at {} changed the size of the stack from {} to {}",
+                   self.location, self.top, top);
+    }
 }
 
-pub unsafe fn from_handle_mut(vm: HSQUIRRELVM) -> Option<&'static mut SquirrelVM> {
-    get_squirrel_handle_instances().as_mut().unwrap()
-        .get_mut(&ThreadSafeSquirrelVMPointer(vm))
-        .map(|link| unsafe { link.sqvm.as_mut() })
+// Returns the number of parameters that were pushed (sq_pushx)
+pub(crate) type SquirrelFunction = Box<dyn Fn(&mut SquirrelVM) -> SQInteger + Send + 'static>;
+
+// Bound to a native closure as a userdata free variable, which owns the function and drops it
+// once the closure is released
+struct NativeFunction {
+    owner: Arc<SquirrelVMOwner>,
+    // only borrowed immutably, so the function can call itself through a script
+    func: SquirrelFunction
 }
 
-pub unsafe fn add_squirrel_function_link(vm: &mut SquirrelVM, name: &str, func: SquirrelFunction) {
-    let mut instances = get_squirrel_handle_instances();
-    if let Some(link) = instances.as_mut().unwrap()
-        .get_mut(&ThreadSafeSquirrelVMPointer(vm.handle)) {
-        link.functions.insert(name.to_string(), func);
+unsafe extern "C" fn release_native_function(p: SQUserPointer, _: SQInteger) -> SQInteger {
+    let func = unsafe { *(p as *mut *mut NativeFunction) };
+    if !func.is_null() {
+        drop(unsafe { Box::from_raw(func) });
     }
-}
-
-pub(crate) unsafe fn remove_squirrel_handle(handle: HSQUIRRELVM) {
-    get_squirrel_handle_instances().as_mut().unwrap().remove(&ThreadSafeSquirrelVMPointer(handle));
+    1
 }
 
 unsafe extern "C" fn sq_function_base(handle: HSQUIRRELVM) -> SQInteger {
-    // this requires that functions are registered with a name using sq_setnativeclosurename
-    let mut info: MaybeUninit<SQStackInfos> = MaybeUninit::uninit();
-    unsafe { sq_stackinfos(handle, 0, info.as_mut_ptr()) };
-    let info = unsafe { info.assume_init() };
-    if info.funcname == std::ptr::null() { return 0; }
-    let func_name = unsafe { std::ffi::CStr::from_ptr(info.funcname).to_str().unwrap() };
-    let mut instances = get_squirrel_handle_instances();
-    let handle_info = instances.as_mut()
-        .unwrap().get(&ThreadSafeSquirrelVMPointer(handle)).unwrap();
-    let sqvm = unsafe { &mut *handle_info.sqvm.as_ptr() };
-    let rust_func = *handle_info.functions.get(func_name).unwrap();
-    // drop SQUIRREL_HANDLE_INSTANCES so other threads can run Squirrel scripts at the same time
-    drop(instances);
-    rust_func(sqvm)
+    // the closure's free variable is pushed after the arguments. it's popped so that arguments
+    // keep the same stack index.
+    let mut p: SQUserPointer = std::ptr::null_mut();
+    if unsafe { sq_getuserdata(handle, -1, &mut p, std::ptr::null_mut()) } != 0 { return 0; }
+    let native = unsafe { &**(p as *mut *const NativeFunction) };
+    unsafe { sq_poptop(handle) };
+    // the function may be called from a thread (e.g a generator), so the view uses the calling
+    // handle rather than the VM's main one
+    let mut sqvm = SquirrelVM::view(handle, native.owner.clone());
+    sqvm.release_dropped();
+    // unwinding into the VM would abort, so panics are thrown to the script instead
+    match std::panic::catch_unwind(AssertUnwindSafe(|| (native.func)(&mut sqvm))) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            sqvm.throw_error(&format!("native function panicked: {}", msg))
+        }
+    }
 }
 
 pub trait SquirrelDebugCallback: Default + Debug {
@@ -213,6 +258,9 @@ impl SquirrelDebugCallback for SquirrelDebugCallbackBasic {
         if let Some(cb) = self.debug_hook_cb {
             crate::print_cb::register_debug_hook_callback(handle, cb);
         }
+        if let Some(cb) = self.runtime_error_cb {
+            crate::print_cb::register_runtime_error_callback(handle, cb);
+        }
         unsafe {
             sq_setprintfunc(
                 handle, Some(crate::print_cb::sq_print_callback_cpp),
@@ -220,10 +268,6 @@ impl SquirrelDebugCallback for SquirrelDebugCallbackBasic {
             );
             sq_setcompilererrorhandler(handle, Some(crate::print_cb::sq_compile_error_callback));
             sq_setnativedebughook(handle, Some(crate::print_cb::sq_debug_hook_callback));
-            if let Some(cb) = self.runtime_error_cb {
-                sq_newclosure(handle, Some(cb), 0);
-                sq_seterrorhandler(handle);
-            }
         }
     }
 
@@ -240,6 +284,11 @@ impl SquirrelDebugCallback for SquirrelDebugCallbackBasic {
         if crate::print_cb::get_debug_hook_callback(vm.handle).is_some() {
             crate::print_cb::remove_debug_hook_callback(vm.handle);
         }
+        if crate::print_cb::get_runtime_error_callback(vm.handle).is_some() {
+            crate::print_cb::remove_runtime_error_callback(vm.handle);
+        }
+        // drop a compile error that was never taken so the entry doesn't outlive the VM
+        crate::print_cb::take_compile_error(vm.handle);
     }
 }
 
@@ -284,12 +333,17 @@ impl<C: SquirrelDebugCallback> SquirrelVMBuilder<C> {
 
     pub fn build(mut self) -> SquirrelVM {
         let handle = unsafe { sq_open((self.stack_size as i64).into()) };
+        let owner = Arc::new(SquirrelVMOwner::new(handle));
         unsafe {
             sq_enabledebuginfo(handle, self.enable_debug_info.into_squirrel());
             sq_notifyallexceptions(handle, self.notify_all_exceptions.into_squirrel());
+            // the owner lives as long as the VM, so the handler can store errors in it
+            sq_pushuserpointer(handle, Arc::as_ptr(&owner) as _);
+            sq_newclosure(handle, Some(crate::print_cb::sq_runtime_error_handler), 1);
+            sq_seterrorhandler(handle);
             self.callbacks.build(handle);
         }
-        SquirrelVM { handle, cleanup_cb: C::cleanup }
+        SquirrelVM { handle, owner, cleanup_cb: Some(C::cleanup) }
     }
 }
 
@@ -322,25 +376,34 @@ macro_rules! squirrel {
         $crate::squirrel!($vm $name($($val, $ty),*) -> ())
     };
     ($vm:ident $name:ident($($val:expr, $ty:ty),* $(,)?) -> $ret:ty) => {
+        $crate::squirrel!($vm env = $crate::table::SqTable::root(&$vm), $name($($val, $ty),*) -> $ret)
+    };
+    // Calling with an explicit environment: env is any object that derefs to SqObjectRef (e.g a
+    // table or class instance), which is passed as this
+    ($vm:ident env = $env:expr, $name:ident($($val:expr, $ty:ty),* $(,)?)) => {
+        $crate::squirrel!($vm env = $env, $name($($val, $ty),*) -> ())
+    };
+    ($vm:ident env = $env:expr, $name:ident($($val:expr, $ty:ty),* $(,)?) -> $ret:ty) => {
         {
             let n = stringify!($name);
+            let env: &$crate::object::SqObjectRef = &$env;
             let handle: squirrel::squirrel_sys::bindings::root::HSQUIRRELVM = unsafe { $vm.raw() };
+            let _check = $vm.stack_check();
+            let _guard = $vm.stack_guard();
             unsafe {
                 squirrel::squirrel_sys::bindings::root::sq_pushroottable(handle);
                 squirrel::squirrel_sys::bindings::root::sq_pushstring(handle, n.as_ptr() as _, n.len() as _);
                 let res = squirrel::squirrel_sys::bindings::root::sq_get(handle, -2); // get function from root table
                 if res == 0 {
-                    squirrel::squirrel_sys::bindings::root::sq_push(handle, -2); // root table
+                    $vm.push(env); // this, which panics if env belongs to another VM
                     let args = (1 + $crate::sqvm_call_count_type_args!($($ty),*)) as i64;
                     $crate::sqvm_call_push_param!($vm $($val, $ty),*);
+                    $vm.clear_runtime_error();
                     let res = squirrel::squirrel_sys::bindings::root::sq_call(handle, args, true.into(), true.into());
+                    // the guard cleans up the stack once the result is read
                     match res {
-                        0 => {
-                            let val: $ret = $vm.get::<$ret>(1)?;
-                            squirrel::squirrel_sys::bindings::root::sq_pop(handle, 3);
-                            Ok::<$ret, $crate::err::SquirrelError>(val)
-                        },
-                        _ => Err($crate::err::SquirrelError::ErrorWhileCalling)
+                        0 => $vm.get::<$ret>(1),
+                        _ => Err($vm.take_runtime_error())
                     }
                 } else {
                     Err($crate::err::SquirrelError::CouldNotFindFunction(n.to_string()))
@@ -362,6 +425,8 @@ macro_rules! squirrel {
         {
             let n = stringify!($name);
             let handle: squirrel::squirrel_sys::bindings::root::HSQUIRRELVM = unsafe { $vm.raw() };
+            let _check = $vm.stack_check();
+            let _guard = $vm.stack_guard();
             unsafe {
                 squirrel::squirrel_sys::bindings::root::sq_pushroottable(handle);
                 squirrel::squirrel_sys::bindings::root::sq_pushstring(handle, n.as_ptr() as _, n.len() as _);
@@ -370,16 +435,14 @@ macro_rules! squirrel {
                     squirrel::squirrel_sys::bindings::root::sq_push(handle, -2); // root table
                     let args = (1 + $crate::sqvm_call_count_type_args!($($ty),*)) as i64;
                     $crate::sqvm_call_push_param!($vm $($val, $ty),*);
+                    $vm.clear_runtime_error();
                     let res = squirrel::squirrel_sys::bindings::root::sq_call(handle, args, true.into(), true.into());
+                    // the guard cleans up the stack once the result is read
                     match res {
-                        0 => {
-                            let val: $ret = $vm.get::<$ret>(1)?;
-                            squirrel::squirrel_sys::bindings::root::sq_pop(handle, 3);
-                            Ok::<$ret, $crate::err::SquirrelError>(val)
-                        },
+                        0 => $vm.get::<$ret>(1),
                         _ => {
                             let _: Result<$sty, _> = $vm.get::<$sty>(1);
-                            Err($crate::err::SquirrelError::ErrorWhileCalling)
+                            Err($vm.take_runtime_error())
                         }
                     }
                 } else {
@@ -390,16 +453,26 @@ macro_rules! squirrel {
     };
 }
 
-// const SQ_VMSTATE_IDLE         : i64 = 0;
+pub(crate) const SQ_VMSTATE_IDLE         : i64 = 0;
 // const SQ_VMSTATE_RUNNING      : i64 = 1;
-const SQ_VMSTATE_SUSPENDED    : i64 = 2;
+pub(crate) const SQ_VMSTATE_SUSPENDED    : i64 = 2;
+
+/// Passed as nparams to [`SqTable::add_function_checked`] to take the number of parameters from
+/// the length of the typemask (SQ_MATCHTYPEMASKSTRING)
+pub const PARAMS_FROM_TYPEMASK: SQInteger = SQ_MATCHTYPEMASKSTRING as SQInteger;
 
 #[derive(Debug)]
 pub struct SquirrelVM {
     pub(crate) handle: HSQUIRRELVM,
-    cleanup_cb: unsafe fn(&mut Self)
+    pub(crate) owner: Arc<SquirrelVMOwner>,
+    // None for views of a VM that's owned elsewhere, which don't close it when dropped
+    cleanup_cb: Option<unsafe fn(&mut Self)>
 }
 
+// Values stored in the VM (native functions, class instances and userdata) are required to be Send,
+// and handles don't touch the VM when they're dropped on another thread
+unsafe impl Send for SquirrelVM {}
+
 impl SquirrelVM {
     pub fn new() -> SquirrelVMBuilder {
         SquirrelVMBuilder::default()
@@ -407,6 +480,12 @@ impl SquirrelVM {
 }
 
 impl SquirrelVM {
+    // A view of the VM that uses the given handle, which is passed to native functions. Unlike
+    // SquirrelVM itself, this doesn't close the VM when it's dropped.
+    pub(crate) fn view(handle: HSQUIRRELVM, owner: Arc<SquirrelVMOwner>) -> Self {
+        Self { handle, owner, cleanup_cb: None }
+    }
+
     pub fn version() -> (u32, u32) {
         let raw = unsafe { sq_getversion() };
         ((raw / 100) as u32, (raw % 100) as u32)
@@ -421,6 +500,39 @@ impl SquirrelVM {
         T::push(value, self);
     }
 
+    /// Throws an error with the given message to the script. This should be returned from a native
+    /// function, e.g `return vm.throw_error("invalid unit")`.
+    pub fn throw_error(&mut self, msg: &str) -> SQInteger {
+        let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+        unsafe { sq_throwerror(self.handle, msg.as_ptr()) }
+    }
+
+    /// Clears the error stored by the last call that failed. Errors are also stored for exceptions
+    /// that are caught when notify_all_exceptions is enabled, so this is called before each call
+    /// that reads the error with [`SquirrelVM::take_runtime_error`].
+    #[doc(hidden)]
+    pub fn clear_runtime_error(&self) {
+        *self.owner.last_error.lock().unwrap() = None;
+        unsafe { sq_reseterror(self.handle) };
+    }
+
+    /// Returns the error from the last call that failed, along with the call stack at the point
+    /// where it was thrown. This is used by the call APIs after `sq_call` fails.
+    #[doc(hidden)]
+    pub fn take_runtime_error(&self) -> SquirrelError {
+        if let Some(err) = self.owner.last_error.lock().unwrap().take() {
+            return err;
+        }
+        // the error handler was replaced, so only the message is available
+        let message = unsafe {
+            sq_getlasterror(self.handle);
+            let message = crate::print_cb::error_message(self.handle, -1);
+            sq_poptop(self.handle);
+            message
+        };
+        SquirrelError::Runtime { message, stack: vec![] }
+    }
+
     // pop
     pub fn pop_top(&mut self) {
         unsafe { sq_poptop(self.handle) }
@@ -431,18 +543,18 @@ impl SquirrelVM {
     }
 
     // get
-    pub fn get<T>(&self, index: usize) -> Result<T, SquirrelError> where T: CanSquirrel {
-        T::get(self, index).map(|v| T::from_squirrel(v))
+    pub fn get<T>(&self, index: impl Into<StackIndex>) -> Result<T, SquirrelError> where T: CanSquirrel {
+        T::get(self, index.into()).map(|v| T::from_squirrel(v))
     }
 
     // get_type
-    pub unsafe fn get_type(&self, index: usize) -> SQObjectType {
-        unsafe { sq_gettype(self.handle, -(index as i64) )}
+    pub unsafe fn get_type(&self, index: impl Into<StackIndex>) -> SQObjectType {
+        unsafe { sq_gettype(self.handle, index.into().raw()) }
     }
 
     // setforeignptr
     pub unsafe fn set_this<'a, T>(&mut self, p: &'a mut T)
-    where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>> + 'a {
+    where &'a mut T: CanSquirrel<Into = UserPointer<T>> + 'a {
         unsafe { sq_setforeignptr(self.handle, p as *const T as _) };
     }
 
@@ -452,24 +564,117 @@ impl SquirrelVM {
 
     // getforeignptr
     pub unsafe fn get_this<'a, T>(&self) -> Result<&'a mut T, SquirrelError>
-    where &'a mut T: CanSquirrel<Into = UserPointer<&'a mut T>> {
+    where &'a mut T: CanSquirrel<Into = UserPointer<T>> {
         match unsafe { sq_getforeignptr(self.handle) } {
             v if v == std::ptr::null_mut() => Err(SquirrelError::ForeignPointerNotSet),
             v => Ok(unsafe { &mut *(v as *mut T) })
         }
     }
 
+    /// Gets the Rust value owned by the class instance at the given stack index. The instance's
+    /// class must have been registered for T with [`SquirrelVM::register_class`].
+    pub unsafe fn get_instance<'a, T>(&self, index: impl Into<StackIndex>) -> Result<&'a mut T, SquirrelError>
+    where T: SquirrelClass {
+        let index = index.into().raw();
+        let mut ptr: SQUserPointer = std::ptr::null_mut();
+        let res = unsafe { sq_getinstanceup(self.handle, index, &mut ptr, type_tag::<T>(), false.into()) };
+        if res != 0 {
+            return Err(SquirrelError::TypeTagMismatch {
+                expected: std::any::type_name::<T>().to_string(),
+                found: stack_type_name(self.handle, index)
+            });
+        }
+        match ptr.is_null() {
+            true => Err(SquirrelError::InstanceNotInitialized),
+            false => Ok(unsafe { &mut *(ptr as *mut T) })
+        }
+    }
+
+    /// Moves the value into the instance that's being constructed. This should be called from a
+    /// class constructor, where the new instance is `this`.
+    pub fn set_instance<T>(&mut self, value: T) -> Result<(), SquirrelError>
+    where T: SquirrelClass {
+        unsafe { set_instance_up(self, 1, value) }
+    }
+
+    /// Starts registering T as a native class. The class is added to the root table once
+    /// [`SqClassBuilder::build`] is called.
+    pub fn register_class<T>(&mut self) -> SqClassBuilder<'_, T>
+    where T: SquirrelClass {
+        SqClassBuilder::new(self)
+    }
+
+    /// Creates a guard which restores the current size of the stack once it's dropped
+    #[track_caller]
+    pub fn stack_guard(&self) -> StackGuard {
+        StackGuard {
+            owner: self.owner.clone(),
+            handle: self.handle,
+            top: unsafe { sq_gettop(self.handle) },
+            #[cfg(debug_assertions)]
+            location: std::panic::Location::caller()
+        }
+    }
+
+    // Created at the start of each public API call to report stack leaks in debug builds. Objects
+    // whose handles were dropped since the last call are released here as well.
+    #[doc(hidden)]
+    #[track_caller]
+    pub fn stack_check(&self) -> StackCheck {
+        self.release_dropped();
+        StackCheck {
+            #[cfg(debug_assertions)]
+            owner: self.owner.clone(),
+            #[cfg(debug_assertions)]
+            handle: self.handle,
+            #[cfg(debug_assertions)]
+            top: unsafe { sq_gettop(self.handle) },
+            #[cfg(debug_assertions)]
+            location: std::panic::Location::caller()
+        }
+    }
+
+    // Releases the objects whose last handle was dropped, which may have been on another thread
+    pub(crate) fn release_dropped(&self) {
+        if !self.owner.has_released.swap(false, Ordering::Acquire) { return }
+        // taken before releasing, since release hooks can drop more handles
+        let released = std::mem::take(&mut *self.owner.released.lock().unwrap());
+        for mut obj in released {
+            unsafe { sq_release(self.owner.handle, &mut obj) };
+        }
+    }
+
     pub fn get_stack_len(&mut self) -> usize {
         unsafe { sq_gettop(self.handle) as _ }
     }
 
-    fn try_compile(&self, bytes: &str, path: &str) -> Result<(), SquirrelError> {
-        match unsafe { sq_compilebuffer(self.handle, bytes.as_ptr() as _, bytes.len() as i64, path.as_ptr() as _, true.into()) } {
+    pub(crate) fn try_compile(&self, bytes: &str, path: &str) -> Result<(), SquirrelError> {
+        let source = CString::new(path.replace('\0', "")).unwrap_or_default();
+        match unsafe { sq_compilebuffer(self.handle, bytes.as_ptr() as _, bytes.len() as i64, source.as_ptr(), true.into()) } {
             0 => Ok(()),
-            _ => Err(SquirrelError::CouldNotCompileSource)
+            _ => Err(self.take_compile_error(bytes, path))
         }
     }
 
+    fn take_compile_error(&self, bytes: &str, path: &str) -> SquirrelError {
+        let (desc, source, line, column) = crate::print_cb::take_compile_error(self.handle)
+            .unwrap_or_else(|| {
+                // the compiler error handler was replaced, so only the description is available
+                let desc = unsafe {
+                    sq_getlasterror(self.handle);
+                    let desc = crate::print_cb::error_message(self.handle, -1);
+                    sq_poptop(self.handle);
+                    desc
+                };
+                (desc, path.to_string(), 0, 0)
+            });
+        let snippet = match line {
+            n if n > 0 => bytes.lines().nth(n as usize - 1).unwrap_or_default().trim_end().to_string(),
+            _ => String::new()
+        };
+        SquirrelError::Compile { desc, source, line, column, snippet }
+    }
+
     unsafe extern "C" fn read_stream(up: SQUserPointer, out: SQUserPointer, mut size: SQInteger) -> SQInteger {
         let pcursor = unsafe { &mut *(up as *mut Cursor<&[u8]>) };
         let plen = pcursor.get_ref().len();
@@ -498,18 +703,23 @@ impl SquirrelVM {
     }
 
     fn import_text_inner(&mut self, buf: &str, src: &str) -> Result<(), SquirrelError> {
+        // pops the compiled closure
+        let _guard = self.stack_guard();
         self.try_compile(buf, src)?;
         unsafe {
             // call main to import functions
             sq_pushroottable(self.handle);
-            sq_call(self.handle, 1, false.into(), true.into());
-            sq_poptop(self.handle);
+            self.clear_runtime_error();
+            if sq_call(self.handle, 1, false.into(), true.into()) != 0 {
+                return Err(self.take_runtime_error());
+            }
         }
         Ok(())
     }
 
     /// Compiles and imports a squirrel source file (.nut) from the given file path.
     pub fn import_text_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let _check = self.stack_check();
         let path_str = path.as_ref().to_str().unwrap();
         let buf = std::fs::read_to_string(path.as_ref())?;
         self.import_text_inner(&buf, path_str)?;
@@ -518,23 +728,29 @@ impl SquirrelVM {
 
     /// Compiles and imports a squirrel source file (.nut) from the string.
     pub fn import_text_from_str(&mut self, buf: &str) -> Result<(), SquirrelError> {
+        let _check = self.stack_check();
         self.import_text_inner(buf, &self.source_name())
     }
 
 
     fn import_binary_inner(&mut self, buf: &[u8], src: &str) -> Result<(), SquirrelError> {
+        // pops the compiled closure
+        let _guard = self.stack_guard();
         self.try_read(buf, src)?;
         unsafe {
             // call main to import functions
             sq_pushroottable(self.handle);
-            sq_call(self.handle, 1, false.into(), true.into());
-            sq_poptop(self.handle);
+            self.clear_runtime_error();
+            if sq_call(self.handle, 1, false.into(), true.into()) != 0 {
+                return Err(self.take_runtime_error());
+            }
         }
         Ok(())
     }
 
     /// Imports a squirrel bytecode file (.cnut) from the given file path.
     pub fn import_binary_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let _check = self.stack_check();
         let path_str = path.as_ref().to_str().unwrap();
         let buf = std::fs::read(path.as_ref())?;
         self.import_binary_inner(&buf, path_str)?;
@@ -543,48 +759,124 @@ impl SquirrelVM {
 
     /// Imports a squirrel bytecode file (.cnut) from the byte slice.
     pub fn import_binary_from_slice(&mut self, buf: &[u8]) -> Result<(), SquirrelError> {
+        let _check = self.stack_check();
         self.import_binary_inner(&buf, &self.source_name())?;
         Ok(())
     }
 
-    fn add_function_link(&mut self, name: &str, func: SquirrelFunction) {
-        if unsafe { !check_squirrel_handle(self) } {
-            unsafe { add_squirrel_handle(self) };
-        }
-        unsafe { add_squirrel_function_link(self, name, func) };
-    }
-
-    // add function
-    pub fn add_function(&mut self, name: &str, func: SquirrelFunction) -> Result<(), SquirrelError> {
+    // Pushes a native closure which calls func. The closure owns func through its free variable,
+    // so names only need to be unique for debug info. If params_check is set, Squirrel checks the
+    // number of parameters (including this) and their typemask before the function is called.
+    pub(crate) fn push_native_closure(&mut self, name: &str, func: SquirrelFunction, params_check: Option<(SQInteger, &str)>) -> Result<(), SquirrelError> {
+        let native = Box::into_raw(Box::new(NativeFunction {
+            owner: self.owner.clone(),
+            func
+        }));
         unsafe {
-            sq_pushroottable(self.handle);
-            sq_pushstring(self.handle, name.as_ptr() as _, name.len() as _);
-            sq_newclosure(self.handle, Some(sq_function_base), 0);
+            let p = sq_newuserdata(self.handle, size_of::<*mut NativeFunction>() as _) as *mut *mut NativeFunction;
+            *p = native;
+            sq_setreleasehook(self.handle, -1, Some(release_native_function));
+            sq_newclosure(self.handle, Some(sq_function_base), 1);
             let cname = name.as_bytes().last().map_or(
                 name.to_owned(),
                 |i| if *i != 0 { format!("{}\0", name) } else { name.to_owned() });
             let res = sq_setnativeclosurename(self.handle, -1, cname.as_ptr() as _);
             if res != 0 { return Err(SquirrelError::CouldNotSetNativeClosureName) }
-            let res = sq_newslot(self.handle, -3, false.into());
-            if res != 0 { return Err(SquirrelError::CouldNotAddFunction) }
-            self.add_function_link(name, func);
-            sq_poptop(self.handle);
-            Ok(())
         }
+        if let Some((nparams, typemask)) = params_check {
+            let mask = CString::new(typemask).map_err(|_| SquirrelError::InvalidTypeMask(typemask.to_owned()))?;
+            if unsafe { sq_setparamscheck(self.handle, nparams, mask.as_ptr()) } != 0 {
+                return Err(SquirrelError::InvalidTypeMask(typemask.to_owned()));
+            }
+        }
+        Ok(())
     }
 
-    pub unsafe fn add_function_raw(&mut self, name: &str, func: SQFUNCTION) -> Result<(), SquirrelError> {
-        unsafe {
-            sq_pushroottable(self.handle);
-            sq_pushstring(self.handle, name.as_ptr() as _, name.len() as _);
-            sq_newclosure(self.handle, func, 0);
-            let res = sq_setnativeclosurename(self.handle, -1, name.as_ptr() as _);
-            if res != 0 { return Err(SquirrelError::CouldNotSetNativeClosureName) }
-            let res = sq_newslot(self.handle, -3, false.into());
-            if res != 0 { return Err(SquirrelError::CouldNotAddFunction) }
-            sq_poptop(self.handle);
-            Ok(())
+    /// Gets the table at the given path from the root table, where each table is separated by a
+    /// `.` (e.g `"Battle.Unit"`). Tables that don't exist yet are created. An empty path returns
+    /// the root table.
+    pub fn namespace(&mut self, path: &str) -> Result<SqTable, SquirrelError> {
+        let _check = self.stack_check();
+        let mut table = SqTable::root(self);
+        for name in path.split('.').filter(|n| !n.is_empty()) {
+            table = table.get_or_create_table(self, name)?;
         }
+        Ok(table)
+    }
+
+    /// Gets the object at the given path from the root table, where each key is separated by a `.`
+    /// (e.g `"Battle.Unit.heal"`). Keys can be slots of tables, classes and instances, or indices
+    /// of arrays.
+    pub fn get_path(&mut self, path: &str) -> Result<SqObjectRef, SquirrelError> {
+        let _check = self.stack_check();
+        let _guard = self.stack_guard();
+        unsafe { sq_pushroottable(self.handle) };
+        for key in path.split('.').filter(|k| !k.is_empty()) {
+            let is_array = unsafe { sq_gettype(self.handle, -1) } == tagSQObjectType_OT_ARRAY;
+            match key.parse::<SQInteger>() {
+                Ok(i) if is_array => unsafe { sq_pushinteger(self.handle, i) },
+                _ => unsafe { sq_pushstring(self.handle, key.as_ptr() as _, key.len() as _) }
+            }
+            if unsafe { sq_get(self.handle, -2) } != 0 {
+                return Err(SquirrelError::KeyNotFound(key.to_string()));
+            }
+        }
+        Ok(SqObjectRef::take_top(self))
+    }
+
+    /// Gets a handle to the function at the given path, see [`SquirrelVM::get_path`]. The handle
+    /// can be called repeatedly, e.g `vm.get_function::<(u32, String), bool>("Battle.can_use")?`.
+    pub fn get_function<Args, Ret>(&mut self, path: &str) -> Result<SqFunction<Args, Ret>, SquirrelError>
+    where Args: SquirrelArgs, Ret: CanSquirrel {
+        self.get_path(path)?.try_into()
+            .map_err(|_| SquirrelError::CouldNotFindFunction(path.to_string()))
+    }
+
+    /// Calls the function at the given path with a tuple of arguments, e.g
+    /// `vm.call::<_, (u32, u32)>("get_pos", (unit,))`. Functions that return several values can
+    /// return them in an array, which is read into a tuple.
+    pub fn call<Args, Ret>(&mut self, path: &str, args: Args) -> Result<Ret, SquirrelError>
+    where Args: SquirrelArgs, Ret: CanSquirrel {
+        let _check = self.stack_check();
+        self.get_function::<Args, Ret>(path)?.call(self, args)
+    }
+
+    /// Calls the function at the given path with `env` as `this`, e.g an entity table or a class
+    /// instance. See [`SquirrelVM::call`].
+    pub fn call_with_env<Args, Ret>(&mut self, path: &str, env: &SqObjectRef, args: Args) -> Result<Ret, SquirrelError>
+    where Args: SquirrelArgs, Ret: CanSquirrel {
+        let _check = self.stack_check();
+        self.get_function::<Args, Ret>(path)?.call_with_env(self, env, args)
+    }
+
+    /// Adds a native function to the root table. The function can capture state, which is dropped
+    /// once Squirrel releases the function. Captured state needs to be `Send`, since the VM can be
+    /// moved to another thread.
+    pub fn add_function<F>(&mut self, name: &str, func: F) -> Result<(), SquirrelError>
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        let _check = self.stack_check();
+        SqTable::root(self).add_function(self, name, func)
+    }
+
+    /// Adds a native function to the root table, which Squirrel only calls if it's given nparams
+    /// parameters (including `this`) that match the typemask. Otherwise, an error is thrown to the
+    /// script. See [`SqTable::add_function_checked`].
+    pub fn add_function_checked<F>(&mut self, name: &str, nparams: SQInteger, typemask: &str, func: F) -> Result<(), SquirrelError>
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        let _check = self.stack_check();
+        SqTable::root(self).add_function_checked(self, name, nparams, typemask, func)
+    }
+
+    /// Adds a native function to the table at the given path, see [`SquirrelVM::namespace`]
+    pub fn add_function_in<F>(&mut self, path: &str, name: &str, func: F) -> Result<(), SquirrelError>
+    where F: Fn(&mut SquirrelVM) -> SQInteger + Send + 'static {
+        let _check = self.stack_check();
+        self.namespace(path)?.add_function(self, name, func)
+    }
+
+    pub unsafe fn add_function_raw(&mut self, name: &str, func: SQFUNCTION) -> Result<(), SquirrelError> {
+        let _check = self.stack_check();
+        unsafe { SqTable::root(self).add_function_raw(self, name, func) }
     }
 
     // suspend/wakeup
@@ -615,27 +907,24 @@ impl SquirrelVM {
     }
 
     pub fn get_global_symbol_keys(&self) -> Result<HashSet<String>, SquirrelError> {
-        let mut keys = HashSet::new();
-        unsafe {
-            sq_pushroottable(self.handle);
-            sq_pushnull(self.handle); // null iterator
-            while sq_next(self.handle, -2) == 0 {
-                // value is -1, key is -2
-                keys.insert(self.get::<String>(2)?);
-                // pop key and value before next iteration
-                sq_pop(self.handle, 2);
-            }
-            sq_pop(self.handle, 2); // pops the null iterator and root table
-        }
-        Ok(keys)
+        let _check = self.stack_check();
+        SqTableIter::<String, SqObjectRef>::new(self, &SqTable::root(self))
+            .map(|v| v.map(|(key, _)| key))
+            .collect()
     }
 }
 
 impl Drop for SquirrelVM {
     fn drop(&mut self) {
+        let Some(cleanup_cb) = self.cleanup_cb else { return };
         unsafe {
-            remove_squirrel_handle(self.handle);
-            (self.cleanup_cb)(self);
+            cleanup_cb(self);
+            // closing the VM releases every object, including the ones still waiting to be released.
+            // the lock keeps a handle that's being dropped from queueing itself after this.
+            let mut released = self.owner.released.lock().unwrap();
+            self.owner.alive.store(false, Ordering::Release);
+            released.clear();
+            drop(released);
             sq_close(self.handle);
         }
     }
@@ -1,8 +1,21 @@
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use squirrel::obj_type::UserPointer;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use squirrel::class::SquirrelClass;
+use squirrel::err::SquirrelError;
+use squirrel::function::SqFunction;
+use squirrel::obj_type::{StackIndex, UserPointer};
+use squirrel::args::Args;
+use squirrel::array::SqArray;
+use squirrel::object::{ArithOp, SqObjectRef};
+use squirrel::table::SqTable;
+use squirrel::thread::{SqGeneratorIter, SqThread, ThreadState};
 use squirrel::squirrel;
 use squirrel::type_cnv::CanSquirrel;
-use squirrel::vm::{SquirrelDebugCallback, SquirrelVM};
+use squirrel::userdata::UserData;
+use squirrel::value::SqValue;
+use squirrel::vm::{SquirrelDebugCallback, SquirrelVM, PARAMS_FROM_TYPEMASK};
 
 #[test]
 fn check_version() -> Result<(), Box<dyn Error>> {
@@ -84,28 +97,48 @@ impl Default for TestUnit {
 }
 
 impl<'a> CanSquirrel for &'a TestUnit {
-    type Into = UserPointer<Self>;
+    type Into = UserPointer<TestUnit>;
     const RETURNS: bool = true;
 
     fn into_squirrel(&self) -> Self::Into {
-        UserPointer::<Self>::new(self)
+        UserPointer::<TestUnit>::new(*self)
     }
 
     fn from_squirrel(v: Self::Into) -> Self {
-        unsafe { *v.as_ptr() }
+        unsafe { &*v.as_ptr() }
     }
 }
 
 impl<'a> CanSquirrel for &'a mut TestUnit {
-    type Into = UserPointer<Self>;
+    type Into = UserPointer<TestUnit>;
     const RETURNS: bool = true;
 
     fn into_squirrel(&self) -> Self::Into {
-        UserPointer::<Self>::new(self)
+        UserPointer::<TestUnit>::new(&**self)
     }
 
     fn from_squirrel(v: Self::Into) -> Self {
-        unsafe { *v.as_ptr() }
+        unsafe { &mut *v.as_ptr() }
+    }
+}
+
+impl SquirrelClass for TestUnit {
+    const CLASS_NAME: &'static str = "TestUnit";
+
+    fn get_property(&self, vm: &mut SquirrelVM, name: &str) -> bool {
+        match name {
+            "hp" => { vm.push(&self.hp); true },
+            "mp" => { vm.push(&self.mp); true },
+            _ => false
+        }
+    }
+
+    fn set_property(&mut self, vm: &SquirrelVM, name: &str, index: StackIndex) -> Result<bool, SquirrelError> {
+        match name {
+            "hp" => { self.hp = vm.get(index)?; Ok(true) },
+            "mp" => { self.mp = vm.get(index)?; Ok(true) },
+            _ => Ok(false)
+        }
     }
 }
 
@@ -158,4 +191,913 @@ fn call_native_method_with_object() -> Result<(), Box<dyn Error>> {
     squirrel!(sqvm add_hp(&mut unit, &mut TestUnit, 10, u32))?;
     assert_eq!(squirrel!(sqvm get_hp(&unit, &TestUnit) -> u32)?, 60);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn hold_object_reference() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        units <- { hp = 50 };
+        function get_units() { return units; }
+        function is_units(t) { return t == units; }
+    "#)?;
+    let units = squirrel!(sqvm get_units() -> SqObjectRef)?;
+    assert!(units.is::<SqTable>());
+    let units_clone = units.clone();
+    assert_eq!(units, units_clone);
+    assert!(squirrel!(sqvm is_units(units_clone, SqObjectRef) -> bool)?);
+    // handles can outlive the VM that they were created from
+    drop(sqvm);
+    drop(units);
+    Ok(())
+}
+
+#[test]
+fn send_object_reference() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str("units <- { hp = 50 };")?;
+    let units = SqTable::try_from(sqvm.get_path("units")?)?;
+    let copy = units.clone();
+    // the VM and its handles can be moved to another thread and dropped there
+    let hp = std::thread::spawn(move || {
+        let hp = units.get::<_, u32>(&mut sqvm, &"hp".to_string());
+        drop(units);
+        drop(sqvm);
+        hp
+    }).join().unwrap()?;
+    assert_eq!(hp, 50);
+    // a handle that's left on this thread outlives the VM
+    drop(copy);
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "another Squirrel VM")]
+fn push_object_into_another_vm() {
+    let mut first = SquirrelVM::new().build();
+    let mut second = SquirrelVM::new().build();
+    first.import_text_from_str("units <- { hp = 50 };").unwrap();
+    let units = SqTable::root(&first).get::<_, SqObjectRef>(&mut first, &"units".to_string()).unwrap();
+    second.push(&units);
+}
+
+#[test]
+fn table_operations() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        units <- { hp = 50, mp = 30 };
+        function get_hp() { return units.hp; }
+    "#)?;
+    let root = SqTable::root(&sqvm);
+    let units = root.get::<String, SqTable>(&mut sqvm, &"units".to_string())?;
+    assert_eq!(units.len(&mut sqvm), 2);
+    assert_eq!(units.get::<String, u32>(&mut sqvm, &"hp".to_string())?, 50);
+    units.set(&mut sqvm, &"hp".to_string(), &75u32)?;
+    assert_eq!(squirrel!(sqvm get_hp() -> u32)?, 75);
+    assert!(units.set(&mut sqvm, &"id".to_string(), &1u32).is_err());
+    units.new_slot(&mut sqvm, &"id".to_string(), &1u32)?;
+    assert!(units.contains(&mut sqvm, &"id".to_string()));
+    units.remove(&mut sqvm, &"mp".to_string())?;
+    assert!(!units.contains(&mut sqvm, &"mp".to_string()));
+    let mut slots = units.iter::<String, u32>(&mut sqvm).collect::<Result<Vec<_>, _>>()?;
+    slots.sort();
+    assert_eq!(slots, vec![("hp".to_string(), 75), ("id".to_string(), 1)]);
+    let table = SqTable::new(&sqvm);
+    table.new_slot(&mut sqvm, &1u32, &"one".to_string())?;
+    assert_eq!(table.get::<u32, String>(&mut sqvm, &1)?, "one");
+    assert_eq!(sqvm.get_stack_len(), 0);
+    Ok(())
+}
+
+#[test]
+fn array_conversions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function get_levels() { return [1, 2, 3]; }
+        function count(a) { return a.len(); }
+    "#)?;
+    sqvm.add_function("sum", |vm| {
+        let values = vm.get::<Vec<u32>>(1).unwrap();
+        vm.push::<u32>(&values.iter().sum());
+        1
+    })?;
+    assert_eq!(squirrel!(sqvm get_levels() -> Vec<u32>)?, vec![1, 2, 3]);
+    assert_eq!(squirrel!(sqvm get_levels() -> [u32; 3])?, [1, 2, 3]);
+    assert!(squirrel!(sqvm get_levels() -> [u32; 2]).is_err());
+    assert_eq!(squirrel!(sqvm sum(vec![4u32, 5, 6], Vec<u32>) -> u32)?, 15);
+    let slice: &[u32] = &[1, 2];
+    assert_eq!(squirrel!(sqvm count(slice, &[u32]) -> u32)?, 2);
+    let array = SqArray::new(&sqvm, 0);
+    array.append(&mut sqvm, &10u32)?;
+    array.append(&mut sqvm, &30u32)?;
+    array.insert(&mut sqvm, 1, &20u32)?;
+    assert_eq!(array.len(&sqvm), 3);
+    assert_eq!(array.get::<u32>(&sqvm, 1)?, 20);
+    array.set(&mut sqvm, 1, &25u32)?;
+    assert_eq!(array.pop::<u32>(&sqvm)?, 30);
+    array.remove(&sqvm, 0)?;
+    array.resize(&sqvm, 2)?;
+    assert_eq!(array.iter::<SqObjectRef>(&sqvm).count(), 2);
+    assert!(array.get::<u32>(&sqvm, 5).is_err());
+    assert!(matches!(SqArray::new(&sqvm, 0).pop::<u32>(&sqvm), Err(SquirrelError::ArrayEmpty)));
+    assert_eq!(sqvm.get_stack_len(), 0);
+    Ok(())
+}
+
+#[test]
+fn map_conversions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function get_config() { return { hp = 50, mp = 30 }; }
+        function get_bad_config() { return { hp = 50, name = "crab" }; }
+        function get_mp(config) { return config.mp; }
+    "#)?;
+    let config = squirrel!(sqvm get_config() -> HashMap<String, u32>)?;
+    assert_eq!(config.get("hp"), Some(&50));
+    assert_eq!(config.get("mp"), Some(&30));
+    let config = squirrel!(sqvm get_config() -> BTreeMap<String, u32>)?;
+    assert_eq!(config.keys().collect::<Vec<_>>(), vec!["hp", "mp"]);
+    assert_eq!(squirrel!(sqvm get_mp(config, BTreeMap<String, u32>) -> u32)?, 30);
+    match squirrel!(sqvm get_bad_config() -> HashMap<String, u32>) {
+        Err(SquirrelError::GetWrongObjectTypeForKey(key)) => assert_eq!(key, "name"),
+        v => panic!("expected a conversion error for name, got {:?}", v)
+    }
+    Ok(())
+}
+
+#[test]
+fn nullable_values() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function find_unit(id) { return id == 1 ? 50 : null; }
+        function is_null(v) { return v == null; }
+    "#)?;
+    sqvm.add_function("heal", |vm| {
+        let amount = vm.get::<Option<u32>>(1).unwrap();
+        vm.push::<Option<u32>>(&amount.map(|v| v + 10));
+        1
+    })?;
+    assert_eq!(squirrel!(sqvm find_unit(1, u32) -> Option<u32>)?, Some(50));
+    assert_eq!(squirrel!(sqvm find_unit(2, u32) -> Option<u32>)?, None);
+    assert!(squirrel!(sqvm is_null(None::<u32>, Option<u32>) -> bool)?);
+    assert!(!squirrel!(sqvm is_null(Some(5u32), Option<u32>) -> bool)?);
+    assert_eq!(squirrel!(sqvm heal(Some(5u32), Option<u32>) -> Option<u32>)?, Some(15));
+    assert_eq!(squirrel!(sqvm heal(None::<u32>, Option<u32>) -> Option<u32>)?, None);
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_conversions() -> Result<(), Box<dyn Error>> {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Status { Normal, Poisoned(u32), Shield { hp: u32 } }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Unit { name: String, hp: u32, position: (f32, f32), status: Vec<Status>, target: Option<u32> }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Encounter { units: Vec<Unit> }
+
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function total_hp(encounter) {
+            local total = 0;
+            foreach (unit in encounter.units) total += unit.hp;
+            return total;
+        }
+        function make_encounter() {
+            return { units = [
+                { name = "Slime", hp = 10, position = [0, 1.5], status = ["Normal"], target = null },
+                { name = "Bat", hp = 4, position = [2, 3], status = [{ Poisoned = 3 }, { Shield = { hp = 2 } }], target = 0 }
+            ]};
+        }
+        function make_broken() {
+            return { units = [
+                { name = "Slime", hp = 10, position = [0, 0], status = [], target = null },
+                { name = "Bat", hp = "four", position = [0, 0], status = [], target = null }
+            ]};
+        }
+    "#)?;
+    let encounter = Encounter { units: vec![
+        Unit { name: "Slime".to_string(), hp: 10, position: (0., 1.5), status: vec![Status::Normal], target: None },
+        Unit { name: "Bat".to_string(), hp: 4, position: (2., 3.), status: vec![Status::Poisoned(3), Status::Shield { hp: 2 }], target: Some(0) }
+    ]};
+    let top = sqvm.get_stack_len();
+    squirrel::serde::to_vm(&mut sqvm, &encounter)?;
+    let table = sqvm.get::<SqTable>(1)?;
+    sqvm.pop_top();
+    assert_eq!(squirrel!(sqvm total_hp(table, SqTable) -> u32)?, 14);
+
+    let result = squirrel!(sqvm make_encounter() -> SqTable)?;
+    sqvm.push(&result);
+    assert_eq!(squirrel::serde::from_vm::<Encounter>(&sqvm, 1)?, encounter);
+    sqvm.pop_top();
+
+    let broken = squirrel!(sqvm make_broken() -> SqTable)?;
+    sqvm.push(&broken);
+    let err = squirrel::serde::from_vm::<Encounter>(&sqvm, 1).unwrap_err();
+    assert_eq!(err.path_string(), "units[1].hp");
+    sqvm.pop_top();
+    // null can't be used as a key
+    let null_key = BTreeMap::from([(None::<u32>, 1u32)]);
+    assert!(squirrel::serde::to_vm(&mut sqvm, &null_key).is_err());
+    // doubles that would lose precision as a 32 bit float are rejected
+    if size_of::<squirrel::squirrel_sys::bindings::root::SQFloat>() == 4 {
+        assert!(squirrel::serde::to_vm(&mut sqvm, &0.1f64).is_err());
+    }
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}
+
+#[test]
+fn native_class() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    let class = sqvm.register_class::<TestUnit>()
+        .constructor(|vm| {
+            let id = vm.get::<u32>(1).unwrap();
+            vm.set_instance(TestUnit { id, ..Default::default() }).unwrap();
+            0
+        })
+        .method("add_hp", |vm| {
+            let this = unsafe { vm.get_instance::<TestUnit>(2).unwrap() };
+            this.hp += vm.get::<u32>(1).unwrap();
+            0
+        })
+        .build()?;
+    sqvm.import_text_from_str(r#"
+        function spawn() {
+            local unit = TestUnit(2);
+            unit.hp = 75;
+            unit.add_hp(5);
+            return unit;
+        }
+        function get_hp(unit) { return unit.hp; }
+        function get_missing(unit) {
+            try { return unit.missing; } catch (e) { return "not found"; }
+        }
+    "#)?;
+    let unit = squirrel!(sqvm spawn() -> SqObjectRef)?;
+    sqvm.push(&unit);
+    let instance = unsafe { sqvm.get_instance::<TestUnit>(1)? };
+    assert_eq!((instance.id, instance.hp, instance.mp), (2, 80, 30));
+    sqvm.pop_top();
+    assert_eq!(squirrel!(sqvm get_missing(unit, SqObjectRef) -> String)?, "not found");
+    // instances can also be created from Rust
+    let unit = class.create_instance(&mut sqvm, TestUnit { hp: 10, ..Default::default() })?;
+    assert_eq!(squirrel!(sqvm get_hp(unit, SqObjectRef) -> u32)?, 10);
+    // a params check needs a member to apply to
+    let res = sqvm.register_class::<TestUnit>().params_check(2, ".n").build();
+    assert!(matches!(res, Err(SquirrelError::ParamsCheckWithoutMember)));
+    Ok(())
+}
+
+#[test]
+fn script_owned_userdata() -> Result<(), Box<dyn Error>> {
+    struct Inventory {
+        items: Vec<String>,
+        drops: Arc<AtomicUsize>
+    }
+
+    impl Drop for Inventory {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        inventory <- null;
+        function store(v) { inventory = v; }
+        function load() { return inventory; }
+        function clear() { inventory = null; }
+    "#)?;
+    let inventory = UserData::new(&sqvm, Inventory { items: vec!["potion".to_string()], drops: drops.clone() });
+    squirrel!(sqvm store(inventory, UserData<Inventory>))?;
+    drop(inventory);
+    // the script still holds a reference
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+    let inventory = squirrel!(sqvm load() -> UserData<Inventory>)?;
+    inventory.borrow_mut()?.items.insert(1, "ether".to_string());
+    assert_eq!(squirrel!(sqvm load() -> UserData<Inventory>)?.borrow()?.items, vec!["potion", "ether"]);
+    // handles share the value, so conflicting borrows are rejected
+    let other = inventory.clone();
+    let items = inventory.borrow()?;
+    assert!(matches!(other.borrow_mut(), Err(SquirrelError::AlreadyBorrowed)));
+    drop(items);
+    // the type tag prevents reading the userdata as another type
+    assert!(matches!(squirrel!(sqvm load() -> UserData<TestUnit>), Err(SquirrelError::TypeTagMismatch { .. })));
+    squirrel!(sqvm clear())?;
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+    drop((inventory, other));
+    // dropped handles are released by the next call into the VM
+    squirrel!(sqvm clear())?;
+    assert_eq!(drops.load(Ordering::SeqCst), 1);
+    // the value can't be accessed once the VM is closed
+    let inventory = UserData::new(&sqvm, Inventory { items: vec![], drops: drops.clone() });
+    drop(sqvm);
+    assert!(matches!(inventory.borrow(), Err(SquirrelError::VMClosed)));
+    drop(inventory);
+    assert_eq!(drops.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[test]
+fn user_pointer_type_tags() -> Result<(), Box<dyn Error>> {
+    struct Inventory;
+
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function("get_hp", |vm| {
+        match vm.get::<&TestUnit>(1) {
+            Ok(unit) => vm.push::<u32>(&unit.hp),
+            Err(SquirrelError::TypeTagMismatch { found, .. }) => vm.push::<String>(&found),
+            Err(e) => vm.push::<String>(&e.to_string())
+        };
+        1
+    })?;
+    let unit = TestUnit::default();
+    assert_eq!(squirrel!(sqvm get_hp(&unit, &TestUnit) -> u32)?, 50);
+    // a pointer to another type is rejected instead of being reinterpreted
+    let inventory = UserData::new(&sqvm, Inventory);
+    let found = squirrel!(sqvm get_hp(inventory, UserData<Inventory>) -> String)?;
+    assert!(found.starts_with("userdata") && found.contains("Inventory"), "{}", found);
+    // values owned by Squirrel can't be read as a reference, even if they have the same type
+    let owned = UserData::new(&sqvm, TestUnit::default());
+    let found = squirrel!(sqvm get_hp(owned, UserData<TestUnit>) -> String)?;
+    assert!(found.starts_with("userdata"), "{}", found);
+    Ok(())
+}
+
+#[test]
+fn stateful_native_functions() -> Result<(), Box<dyn Error>> {
+    let score = Arc::new(Mutex::new(0u32));
+    let (sender, receiver) = mpsc::channel();
+    let mut sqvm = SquirrelVM::new().build();
+    let state = score.clone();
+    sqvm.add_function("add_score", move |vm| {
+        let mut score = state.lock().unwrap();
+        *score += vm.get::<u32>(1).unwrap();
+        vm.push::<u32>(&score);
+        1
+    })?;
+    let calls = AtomicUsize::new(0);
+    sqvm.add_function("log", move |vm| {
+        let calls = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        sender.send(format!("{}: {}", calls, vm.get::<String>(1).unwrap())).unwrap();
+        0
+    })?;
+    sqvm.import_text_from_str(r#"
+        function play() {
+            log("start");
+            add_score(10);
+            log("end");
+            return add_score(5);
+        }
+    "#)?;
+    assert_eq!(squirrel!(sqvm play() -> u32)?, 15);
+    assert_eq!(*score.lock().unwrap(), 15);
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec!["1: start", "2: end"]);
+    // functions keep working once the VM is moved
+    let mut sqvm = Box::new(sqvm);
+    assert_eq!(squirrel!(sqvm play() -> u32)?, 30);
+    Ok(())
+}
+
+#[test]
+fn reentrant_native_functions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    // the function calls itself through the script until the depth reaches 0
+    sqvm.add_function("recurse", |vm| {
+        let depth = vm.get::<u32>(1).unwrap();
+        let result = match depth {
+            0 => 0,
+            _ => vm.call::<_, u32>("call_recurse", (depth - 1,)).unwrap() + 1
+        };
+        vm.push(&result);
+        1
+    })?;
+    sqvm.import_text_from_str(r#"
+        function call_recurse(depth) { return recurse(depth); }
+    "#)?;
+    assert_eq!(squirrel!(sqvm call_recurse(3u32, u32) -> u32)?, 3);
+    Ok(())
+}
+
+#[test]
+fn duplicate_function_names() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        Battle <- {};
+        function move_to_battle() { Battle.get_id <- get_id; }
+        function ids() { return get_id() * 10 + Battle.get_id(); }
+    "#)?;
+    sqvm.add_function("get_id", |vm| {
+        vm.push::<u32>(&1);
+        1
+    })?;
+    squirrel!(sqvm move_to_battle())?;
+    // replaces the root table's function, while Battle keeps the first one
+    sqvm.add_function("get_id", |vm| {
+        vm.push::<u32>(&2);
+        1
+    })?;
+    assert_eq!(squirrel!(sqvm ids() -> u32)?, 21);
+    Ok(())
+}
+
+#[test]
+fn namespaced_functions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function_in("Battle.Unit", "max_hp", |vm| {
+        vm.push::<u32>(&100);
+        1
+    })?;
+    let items = SqTable::new(&sqvm);
+    items.add_function(&mut sqvm, "max_hp", |vm| {
+        vm.push::<u32>(&5);
+        1
+    })?;
+    SqTable::root(&sqvm).new_slot(&mut sqvm, &"Items".to_string(), &items)?;
+    let battle = sqvm.namespace("Battle")?;
+    sqvm.register_class::<TestUnit>()
+        .static_method("max_hp", |vm| {
+            vm.push::<u32>(&50);
+            1
+        })
+        .build_in(&battle)?;
+    sqvm.import_text_from_str(r#"
+        function max_hp() {
+            return [Battle.Unit.max_hp(), Items.max_hp(), Battle.TestUnit.max_hp()];
+        }
+    "#)?;
+    assert_eq!(squirrel!(sqvm max_hp() -> Vec<u32>)?, vec![100, 5, 50]);
+    // nothing is added to the root table
+    assert!(!SqTable::root(&sqvm).contains(&mut sqvm, &"TestUnit".to_string()));
+    Ok(())
+}
+
+#[test]
+fn checked_native_functions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function_checked("square", 2, &[".", u32::type_mask().as_str()].concat(), |vm| {
+        let p = vm.get::<u32>(1).unwrap();
+        vm.push::<u32>(&(p * p));
+        1
+    })?;
+    sqvm.add_function_checked("find", 2, &[".", Option::<String>::type_mask().as_str()].concat(), |vm| {
+        let name = vm.get::<Option<String>>(1).unwrap();
+        vm.push::<bool>(&name.is_some());
+        1
+    })?;
+    sqvm.import_text_from_str(r#"
+        function call(f) {
+            try { return "" + f(); } catch (e) { return "error"; }
+        }
+        function squares() {
+            return [call(@() square(3)), call(@() square()), call(@() square(1, 2)), call(@() square("3"))];
+        }
+        function finds() {
+            return [call(@() find("unit")), call(@() find(null)), call(@() find(1))];
+        }
+    "#)?;
+    assert_eq!(squirrel!(sqvm squares() -> Vec<String>)?, vec!["9", "error", "error", "error"]);
+    assert_eq!(squirrel!(sqvm finds() -> Vec<String>)?, vec!["true", "false", "error"]);
+    assert!(matches!(sqvm.add_function_checked("bad", 2, ".z", |_| 0), Err(SquirrelError::InvalidTypeMask(_))));
+    Ok(())
+}
+
+#[test]
+fn native_errors_become_exceptions() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function("find_unit", |vm| {
+        match vm.get::<u32>(1) {
+            Ok(1) => {
+                vm.push::<String>(&"hero".to_string());
+                1
+            },
+            Ok(id) => vm.throw_error(&format!("no unit with id {}", id)),
+            Err(e) => vm.throw_error(&e.to_string())
+        }
+    })?;
+    sqvm.add_function("explode", |_| panic!("unit exploded"))?;
+    sqvm.import_text_from_str(r#"
+        function call(f) {
+            try { return f(); } catch (e) { return "error: " + e; }
+        }
+        function run() {
+            return [call(@() find_unit(1)), call(@() find_unit(2)), call(@() explode())];
+        }
+    "#)?;
+    assert_eq!(squirrel!(sqvm run() -> Vec<String>)?, vec![
+        "hero", "error: no unit with id 2", "error: native function panicked: unit exploded"
+    ]);
+    Ok(())
+}
+
+#[test]
+fn runtime_error_reports() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function check_hp(hp) {
+            local limit = 100;
+            if (hp > limit) throw "hp over limit";
+            return hp;
+        }
+        function spawn(hp) {
+            return check_hp(hp);
+        }
+    "#)?;
+    let top = sqvm.get_stack_len();
+    assert_eq!(squirrel!(sqvm spawn(50, u32) -> u32)?, 50);
+    match squirrel!(sqvm spawn(150, u32) -> u32) {
+        Err(SquirrelError::Runtime { message, stack }) => {
+            assert_eq!(message, "hp over limit");
+            let functions: Vec<_> = stack.iter().map(|f| f.function.as_str()).collect();
+            assert_eq!(functions, vec!["check_hp", "spawn"]);
+            assert_eq!(stack[0].line, 4);
+            assert!(stack[0].locals.contains(&("hp".to_string(), "150".to_string())));
+            assert!(stack[0].locals.contains(&("limit".to_string(), "100".to_string())));
+        },
+        v => panic!("expected a runtime error, got {:?}", v)
+    }
+    // the stack is left as it was before the call
+    assert_eq!(sqvm.get_stack_len(), top);
+    match sqvm.import_text_from_str("local units = {}; units.hero.hp = 1;") {
+        Err(SquirrelError::Runtime { message, .. }) => assert!(message.contains("hero")),
+        v => panic!("expected a runtime error, got {:?}", v)
+    }
+    Ok(())
+}
+
+#[test]
+fn compile_error_reports() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    let err = sqvm.import_text_from_str("function heal(unit) {\n    unit.hp += ;\n}").unwrap_err();
+    let display = err.to_string();
+    match err {
+        SquirrelError::Compile { line, column, snippet, .. } => {
+            assert_eq!(line, 2);
+            assert!(column > 0);
+            assert_eq!(snippet, "    unit.hp += ;");
+            // the caret is placed under the column that the error was reported at
+            assert!(display.ends_with(&format!("\n    {}^", " ".repeat(column as usize - 1))));
+        },
+        v => panic!("expected a compile error, got {:?}", v)
+    }
+    // the VM can still import scripts afterwards
+    sqvm.import_text_from_str("function heal(unit) { return 1; }")?;
+    Ok(())
+}
+
+#[test]
+fn typed_function_handles() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function_in("Battle", "double", |vm| {
+        let v = vm.get::<u32>(1).unwrap_or_default();
+        vm.push(&(v * 2));
+        1
+    })?;
+    sqvm.import_text_from_str(r#"
+        Battle.can_use <- function(mp, skill) { return mp >= 50 && skill != "none"; }
+        class Unit {
+            hp = 0;
+            constructor(hp) { this.hp = hp; }
+            function damage(n) { hp -= n; return hp; }
+        }
+        hero <- Unit(100);
+        callbacks <- [@(x) x + 1, @(x) x * 10];
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let can_use = sqvm.get_function::<(u32, String), bool>("Battle.can_use")?;
+    assert!(can_use.call(&mut sqvm, (75, "fire".into()))?);
+    assert!(!can_use.call(&mut sqvm, (25, "fire".into()))?);
+    let double = sqvm.get_function::<(u32,), u32>("Battle.double")?;
+    assert_eq!(double.call(&mut sqvm, (21,))?, 42);
+    // class members are called with an instance as this
+    let hero = sqvm.get_path("hero")?;
+    let damage = sqvm.get_function::<(u32,), u32>("Unit.damage")?.with_env(hero);
+    assert_eq!(damage.call(&mut sqvm, (30,))?, 70);
+    assert_eq!(damage.call(&mut sqvm, (30,))?, 40);
+    let second = sqvm.get_function::<(u32,), u32>("callbacks.1")?;
+    assert_eq!(second.call(&mut sqvm, (5,))?, 50);
+    // handles can be read from tables like any other value
+    let battle = sqvm.namespace("Battle")?;
+    let can_use: SqFunction<(u32, String), bool> = battle.get(&mut sqvm, &"can_use".to_string())?;
+    assert!(can_use.call(&mut sqvm, (50, "ice".into()))?);
+    assert!(matches!(sqvm.get_function::<(), ()>("hero.hp"), Err(SquirrelError::CouldNotFindFunction(_))));
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}
+
+#[test]
+fn tuple_arguments_and_returns() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function get_pos(unit) { return [unit.x, unit.y]; }
+        function swap(pos) { return [pos[1], pos[0]]; }
+        function describe(name, level) { return [name, level * 2, level > 5]; }
+        function sum(a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p) {
+            return a + b + c + d + e + f + g + h + i + j + k + l + m + n + o + p;
+        }
+        function get_stats() { return { hp = 100, mp = 40 }; }
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let unit = SqTable::new(&sqvm);
+    unit.new_slot(&mut sqvm, &"x".to_string(), &3u32)?;
+    unit.new_slot(&mut sqvm, &"y".to_string(), &7u32)?;
+    assert_eq!(sqvm.call::<_, (u32, u32)>("get_pos", (unit,))?, (3, 7));
+    assert_eq!(sqvm.call::<_, (String, u32, bool)>("describe", ("hero".to_string(), 6u32))?,
+               ("hero".to_string(), 12, true));
+    let total: u32 = sqvm.call("sum", (1u32, 2u32, 3u32, 4u32, 5u32, 6u32, 7u32, 8u32,
+                                       9u32, 10u32, 11u32, 12u32, 13u32, 14u32, 15u32, 16u32))?;
+    assert_eq!(total, 136);
+    // tuples are pushed as arrays
+    assert_eq!(sqvm.call::<_, (u32, u32)>("swap", ((5u32, 9u32),))?, (9, 5));
+    // arrays that are too short can't be read into the tuple
+    assert!(matches!(sqvm.call::<_, (u32, u32, u32)>("swap", ((5u32, 9u32),)),
+                     Err(SquirrelError::IndexOutOfRange(2))));
+    #[cfg(feature = "serde")]
+    {
+        use squirrel::serde::Serde;
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Stats { hp: u32, mp: u32 }
+        let Serde(stats) = sqvm.call::<_, Serde<Stats>>("get_stats", ())?;
+        assert_eq!(stats, Stats { hp: 100, mp: 40 });
+    }
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}
+
+#[test]
+fn call_with_environment() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        hp <- 1;
+        function take_damage(n) { hp -= n; return hp; }
+        class Unit {
+            hp = 0;
+            constructor(hp) { this.hp = hp; }
+        }
+        hero <- Unit(80);
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let entity = SqTable::new(&sqvm);
+    entity.new_slot(&mut sqvm, &"hp".to_string(), &50u32)?;
+    assert_eq!(squirrel!(sqvm env = entity, take_damage(10, u32) -> u32)?, 40);
+    assert_eq!(sqvm.call_with_env::<_, u32>("take_damage", &entity, (15u32,))?, 25);
+    let hero = sqvm.get_path("hero")?;
+    assert_eq!(sqvm.call_with_env::<_, u32>("take_damage", &hero, (30u32,))?, 50);
+    let take_damage = sqvm.get_function::<(u32,), u32>("take_damage")?;
+    assert_eq!(take_damage.call_with_env(&mut sqvm, &hero, (5,))?, 45);
+    // the root table is left unchanged
+    assert_eq!(entity.get::<_, u32>(&mut sqvm, &"hp".to_string())?, 25);
+    assert_eq!(SqTable::root(&sqvm).get::<_, u32>(&mut sqvm, &"hp".to_string())?, 1);
+    assert!(matches!(squirrel!(sqvm env = entity, missing()), Err(SquirrelError::CouldNotFindFunction(_))));
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}
+
+#[test]
+fn threads_and_generators() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function event(name) {
+            local answer = ::suspend("hello " + name);
+            local count = ::suspend("you said " + answer);
+            return "done after " + count;
+        }
+        function fail() { ::suspend("first"); throw "event failed"; }
+        function levels(n) { for (local i = 1; i <= n; i++) yield i * 10; return "unused"; }
+        function make_levels(n) { return levels(n); }
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let event = sqvm.get_path("event")?;
+    let mut thread = SqThread::new(&mut sqvm, &event)?;
+    assert!(matches!(thread.start::<_, String>(&mut sqvm, ("hero".to_string(),)),
+                     ThreadState::Yielded(v) if v == "hello hero"));
+    assert!(thread.is_suspended(&sqvm));
+    assert!(matches!(thread.start::<_, String>(&mut sqvm, ("again".to_string(),)),
+                     ThreadState::Error(SquirrelError::ThreadNotIdle)));
+    assert!(matches!(thread.resume::<_, String>(&mut sqvm, &"yes".to_string()),
+                     ThreadState::Yielded(v) if v == "you said yes"));
+    assert!(matches!(thread.resume::<_, String>(&mut sqvm, &3u32),
+                     ThreadState::Returned(v) if v == "done after 3"));
+    assert!(matches!(thread.resume::<_, String>(&mut sqvm, &()),
+                     ThreadState::Error(SquirrelError::ThreadNotSuspended)));
+    let fail = sqvm.get_path("fail")?;
+    let mut thread = SqThread::new(&mut sqvm, &fail)?;
+    assert!(matches!(thread.start::<_, String>(&mut sqvm, ()), ThreadState::Yielded(_)));
+    assert!(matches!(thread.resume::<_, ()>(&mut sqvm, &()),
+                     ThreadState::Error(SquirrelError::Runtime { message, .. }) if message == "event failed"));
+    // generators
+    let generator = sqvm.call::<_, SqObjectRef>("make_levels", (3u32,))?;
+    let levels = SqGeneratorIter::<u32>::new(&mut sqvm, &generator)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(levels, vec![10, 20, 30]);
+    // the second iterator reuses the helper closure cached in the registry
+    let generator = sqvm.call::<_, SqObjectRef>("make_levels", (2u32,))?;
+    let levels = SqGeneratorIter::<u32>::new(&mut sqvm, &generator)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(levels, vec![10, 20]);
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}
+
+// Pushes values and returns early on error, leaving the cleanup to the guard
+fn push_until_error(sqvm: &mut SquirrelVM, table: &SqTable) -> Result<u32, SquirrelError> {
+    let guard = sqvm.stack_guard();
+    assert_eq!(guard.top() as usize, sqvm.get_stack_len());
+    sqvm.push(&1u32);
+    sqvm.push(&"unused".to_string());
+    table.get::<_, u32>(sqvm, &"missing".to_string())
+}
+
+#[test]
+fn stack_guard_restores_stack() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        function fail() { throw "failed"; }
+        values <- [1, 2, 3];
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let root = SqTable::root(&sqvm);
+    assert!(matches!(push_until_error(&mut sqvm, &root), Err(SquirrelError::KeyNotFound(_))));
+    assert_eq!(sqvm.get_stack_len(), top);
+    // error paths leave the stack unchanged
+    assert!(squirrel!(sqvm missing()).is_err());
+    assert!(squirrel!(sqvm fail()).is_err());
+    assert!(sqvm.call::<_, ()>("fail", ()).is_err());
+    assert!(sqvm.get_path("values.10").is_err());
+    assert!(sqvm.import_text_from_str("function broken( {").is_err());
+    assert!(!root.contains(&mut sqvm, &"missing".to_string()));
+    let values = SqArray::try_from(sqvm.get_path("values")?)?;
+    assert!(values.get::<u32>(&sqvm, 10).is_err());
+    assert_eq!(sqvm.get_stack_len(), top);
+    // a guard that outlives its VM does nothing when it's dropped
+    let guard = sqvm.stack_guard();
+    drop(sqvm);
+    drop(guard);
+    Ok(())
+}
+
+#[test]
+fn absolute_and_relative_stack_indices() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.add_function("describe", |vm| {
+        let name = vm.get::<String>(StackIndex::Absolute(2)).unwrap();
+        let level = vm.get::<u32>(StackIndex::Absolute(3)).unwrap();
+        // the same arguments, counted from the top of the stack
+        assert_eq!(vm.get::<String>(StackIndex::Relative(2)).unwrap(), name);
+        assert_eq!(vm.get::<u32>(1).unwrap(), level);
+        let this = vm.get::<SqTable>(StackIndex::Absolute(1)).unwrap();
+        let prefix = this.get::<_, String>(vm, &"prefix".to_string()).unwrap();
+        vm.push(&format!("{}{} (level {})", prefix, name, level));
+        1
+    })?;
+    sqvm.import_text_from_str(r#"
+        prefix <- "hero: ";
+    "#)?;
+    assert_eq!(squirrel!(sqvm describe("knight".to_string(), String, 3, u32) -> String)?, "hero: knight (level 3)");
+    sqvm.push(&10u32);
+    sqvm.push(&20u32);
+    let top = sqvm.get_stack_len();
+    assert_eq!(StackIndex::Relative(2).to_absolute(&sqvm), StackIndex::Absolute(top - 1));
+    assert_eq!(sqvm.get::<u32>(StackIndex::Absolute(top - 1))?, 10);
+    assert_eq!(StackIndex::Absolute(top).raw(), top as i64);
+    assert_eq!(StackIndex::from(1).raw(), -1);
+    sqvm.pop(2);
+    Ok(())
+}
+
+#[test]
+fn variadic_native_functions() -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let mut sqvm = SquirrelVM::new().build();
+    // a prefix followed by any number of values
+    sqvm.add_function_checked("log", -2, ".s", move |vm| {
+        let args = Args::new(vm);
+        let prefix = args.get::<String>(0).unwrap();
+        let values: Vec<String> = args.iter().skip(1).map(|v| match v.unwrap() {
+            SqValue::Integer(i) => i.to_string(),
+            SqValue::String(s) => s,
+            SqValue::Null => "null".to_string(),
+            SqValue::Table(_) => "table".to_string(),
+            _ => "other".to_string()
+        }).collect();
+        assert!(args.get::<u32>(args.len()).is_err());
+        sender.send(format!("{}: {}", prefix, values.join(", "))).unwrap();
+        vm.push(&(args.len() as u32));
+        1
+    })?;
+    sqvm.add_function_checked("sum", PARAMS_FROM_TYPEMASK, ".nn", |vm| {
+        let total = Args::new(vm).iter()
+            .filter_map(|v| match v {
+                Ok(SqValue::Integer(i)) => Some(i),
+                _ => None
+            })
+            .sum::<i64>();
+        vm.push(&total);
+        1
+    })?;
+    sqvm.import_text_from_str(r#"
+        function run() {
+            log("start");
+            log("values", 1, "two", null, {});
+            local errors = 0;
+            try { log(); } catch (e) { errors++; }
+            try { sum(1); } catch (e) { errors++; }
+            return sum(2, 3) + errors * 100;
+        }
+    "#)?;
+    assert_eq!(squirrel!(sqvm run() -> i64)?, 205);
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec!["start: ", "values: 1, two, null, table"]);
+    Ok(())
+}
+
+#[test]
+fn dynamic_values() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        class Unit {}
+        function gen() { yield 1; }
+        stats <- { hp = 50, tags = ["hero", 2.5], owner = null };
+        stats.self_ref <- stats;
+        function copy_of_stats() {
+            local copy = { hp = 50, tags = ["hero", 2.5], owner = null };
+            copy.self_ref <- copy;
+            return copy;
+        }
+        function values() {
+            return [null, 1, 2.5, true, "text", {}, [], gen, print, Unit, Unit(), gen(),
+                    ::newthread(gen), stats.weakref()];
+        }
+    "#)?;
+    let values = sqvm.call::<_, Vec<SqValue>>("values", ())?;
+    let names = values.iter().map(|v| v.type_name()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["null", "integer", "float", "bool", "string", "table", "array", "function",
+                           "function", "class", "instance", "generator", "thread", "weakref"]);
+    assert!(matches!(values[7], SqValue::Closure(_)) && matches!(values[8], SqValue::NativeClosure(_)));
+    assert_eq!(format!("{:?}", &values[..5]), r#"[null, 1, 2.5, true, "text"]"#);
+    assert!(format!("{:?}", values[5]).starts_with("table(0x"));
+    // values can be passed back to scripts
+    let root = SqTable::root(&sqvm);
+    root.new_slot(&mut sqvm, &"value".to_string(), &SqValue::String("passed".to_string()))?;
+    assert_eq!(root.get::<_, String>(&mut sqvm, &"value".to_string())?, "passed");
+    // deep comparisons
+    let stats = SqValue::Table(root.get::<_, SqTable>(&mut sqvm, &"stats".to_string())?);
+    let copy = sqvm.call::<_, SqValue>("copy_of_stats", ())?;
+    assert!(stats != copy);
+    assert!(stats.deep_eq(&mut sqvm, &copy)?);
+    let clone = stats.deep_clone(&mut sqvm)?;
+    assert!(clone != stats && clone.deep_eq(&mut sqvm, &stats)?);
+    let SqValue::Table(table) = &clone else { panic!("expected a table") };
+    assert_eq!(table.get::<_, SqTable>(&mut sqvm, &"self_ref".to_string())?, *table);
+    table.set(&mut sqvm, &"hp".to_string(), &10u32)?;
+    assert!(!clone.deep_eq(&mut sqvm, &stats)?);
+    assert_eq!(root.get::<_, SqTable>(&mut sqvm, &"stats".to_string())?.get::<_, u32>(&mut sqvm, &"hp".to_string())?, 50);
+    Ok(())
+}
+
+#[test]
+fn object_metamethods() -> Result<(), Box<dyn Error>> {
+    let mut sqvm = SquirrelVM::new().build();
+    sqvm.import_text_from_str(r#"
+        class Gold {
+            amount = 0;
+            constructor(amount) { this.amount = amount; }
+            function _tostring() { return amount + "g"; }
+            function _typeof() { return "Gold"; }
+            function _cmp(other) { return amount <=> other.amount; }
+            function _add(other) { return Gold(amount + (typeof other == "integer" ? other : other.amount)); }
+        }
+        small <- Gold(5);
+        large <- Gold(20);
+        plain <- { name = "plain" };
+    "#)?;
+    let top = sqvm.get_stack_len();
+    let small = sqvm.get_path("small")?;
+    let large = sqvm.get_path("large")?;
+    assert_eq!(small.to_display_string(&mut sqvm)?, "5g");
+    assert_eq!(small.type_of(&mut sqvm)?, "Gold");
+    assert_eq!(small.cmp(&mut sqvm, &large)?, std::cmp::Ordering::Less);
+    assert_eq!(large.cmp(&mut sqvm, &small)?, std::cmp::Ordering::Greater);
+    let total = small.add(&mut sqvm, &large)?;
+    assert_eq!(total.to_display_string(&mut sqvm)?, "25g");
+    assert_eq!(total.add(&mut sqvm, &10i64)?.to_display_string(&mut sqvm)?, "35g");
+    // values without metamethods
+    let plain = sqvm.get_path("plain")?;
+    assert_eq!(plain.type_of(&mut sqvm)?, "table");
+    assert!(plain.to_display_string(&mut sqvm)?.starts_with("(table"));
+    let seven = sqvm.get_path("small.amount")?.arith(&mut sqvm, ArithOp::Add, &2i64)?;
+    assert_eq!(i64::try_from(&seven)?, 7);
+    assert_eq!(seven.cmp(&mut sqvm, &7i64)?, std::cmp::Ordering::Equal);
+    assert!(plain.cmp(&mut sqvm, &1i64).is_err());
+    assert!(matches!(plain.mul(&mut sqvm, &2i64), Err(SquirrelError::Runtime { .. })));
+    // each operator is compiled once and reused
+    let registry = SqTable::registry(&sqvm);
+    let add = registry.get::<_, SqObjectRef>(&mut sqvm, &"sqcrab.arith+".to_string())?;
+    small.add(&mut sqvm, &large)?;
+    assert_eq!(registry.get::<_, SqObjectRef>(&mut sqvm, &"sqcrab.arith+".to_string())?, add);
+    assert_eq!(sqvm.get_stack_len(), top);
+    Ok(())
+}